@@ -0,0 +1,76 @@
+use crate::{AssocService, Error, Health, StartableService};
+
+/// Uses an `AssocService` as a `StartableService`, converting its errors to strings. The typed
+/// errors are still available through `start_typed` and `stop_typed`
+pub struct AsStartable<S>(pub S);
+
+impl<S: AssocService> StartableService for AsStartable<S>
+where
+    S::AssocError: Into<Error> + ToString,
+{
+    fn start(&mut self) -> Result<(), String> {
+        self.0.start().map_err(|e| e.to_string())
+    }
+    fn stop(&mut self) -> Result<(), String> {
+        self.0.stop().map_err(|e| e.to_string())
+    }
+    fn health(&self) -> Health {
+        self.0.health()
+    }
+    fn start_typed(&mut self) -> Result<(), Error> {
+        self.0.start().map_err(Into::into)
+    }
+    fn stop_typed(&mut self) -> Result<(), Error> {
+        self.0.stop().map_err(Into::into)
+    }
+}
+
+/// Uses a `StartableService` as an `AssocService`, with string errors kept as `Error::Custom`
+pub struct AsAssoc<S>(pub S);
+
+impl<S: StartableService> AssocService for AsAssoc<S> {
+    type AssocError = Error;
+
+    fn start(&mut self) -> Result<(), Self::AssocError> {
+        self.0.start().map_err(Error::Custom)
+    }
+    fn stop(&mut self) -> Result<(), Self::AssocError> {
+        self.0.stop().map_err(Error::Custom)
+    }
+    fn health(&self) -> Health {
+        self.0.health()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{start_all, DynAssocService, Fault, FaultyService, ServiceThree, ServiceTwo};
+
+    #[test]
+    fn adapt_between_traits() {
+        let mut two = AsStartable(ServiceTwo);
+        let mut three = AsStartable(ServiceThree { fails: true });
+        let mut services: Vec<&mut dyn StartableService> = vec![&mut two, &mut three];
+        assert_eq!(
+            start_all(&mut services),
+            vec![
+                Err("Custom Service error: Service two failed!".to_owned()),
+                Err("Failed to start service".to_owned())
+            ]
+        );
+
+        // Typed errors are kept for callers which report the variant
+        let mut faulty = AsStartable(FaultyService::new(Fault::FailFirst(1)));
+        assert_eq!(faulty.start_typed(), Err(Error::FailedToStart));
+        assert_eq!(faulty.start_typed(), Ok(()));
+
+        let mut two = AsAssoc(ServiceTwo);
+        assert_eq!(
+            AssocService::start(&mut two),
+            Err(Error::Custom("Service two failed!".to_owned()))
+        );
+        let err = two.start_boxed().unwrap_err();
+        assert!(err.is::<Error>());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{AssocService, StartableService};
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// Async counterpart of `StartableService`. Futures are boxed so the trait can still be used
+/// as a trait object, which async functions in traits do not allow
+pub trait AsyncStartableService {
+    fn start(&mut self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(future::ready(Ok(())))
+    }
+    fn stop(&mut self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Async counterpart of `AssocService`
+pub trait AsyncAssocService {
+    type AssocError: Send;
+
+    fn start(&mut self) -> BoxFuture<'_, Result<(), Self::AssocError>>;
+    fn stop(&mut self) -> BoxFuture<'_, Result<(), Self::AssocError>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Async version of `start_service`
+pub async fn start_service_async<S: AsyncAssocService>(s: &mut S) -> Result<(), String>
+where
+    S::AssocError: ToString,
+{
+    s.start().await.map_err(|e| e.to_string())
+}
+
+/// Async version of `start_all`, all services are started concurrently
+pub async fn start_all_async(
+    services: &mut [&mut (dyn AsyncStartableService + Send)],
+) -> Vec<Result<(), String>> {
+    future::join_all(services.iter_mut().map(|s| s.start())).await
+}
+
+/// Runs a blocking service on its own thread so it can be used as an async service without
+/// blocking the executor
+pub struct Blocking<S> {
+    inner: Arc<Mutex<S>>,
+}
+
+impl<S> Blocking<S> {
+    pub fn new(service: S) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(service)),
+        }
+    }
+
+    /// Access to the wrapped service, blocks while it is starting or stopping
+    pub fn lock(&self) -> MutexGuard<'_, S> {
+        lock(&self.inner)
+    }
+}
+
+impl<S: StartableService + Send + 'static> AsyncStartableService for Blocking<S> {
+    fn start(&mut self) -> BoxFuture<'_, Result<(), String>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            run_blocking(move || lock(&inner).start())
+                .await
+                .unwrap_or_else(|_| Err("service panicked while starting".to_owned()))
+        })
+    }
+    fn stop(&mut self) -> BoxFuture<'_, Result<(), String>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            run_blocking(move || lock(&inner).stop())
+                .await
+                .unwrap_or_else(|_| Err("service panicked while stopping".to_owned()))
+        })
+    }
+}
+
+impl<S> AsyncAssocService for Blocking<S>
+where
+    S: AssocService + Send + 'static,
+    S::AssocError: Send + 'static,
+{
+    type AssocError = S::AssocError;
+
+    /// A panic in the wrapped service is resumed when this future is polled
+    fn start(&mut self) -> BoxFuture<'_, Result<(), Self::AssocError>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            run_blocking(move || lock(&inner).start())
+                .await
+                .unwrap_or_else(|p| panic::resume_unwind(p))
+        })
+    }
+    fn stop(&mut self) -> BoxFuture<'_, Result<(), Self::AssocError>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            run_blocking(move || lock(&inner).stop())
+                .await
+                .unwrap_or_else(|p| panic::resume_unwind(p))
+        })
+    }
+}
+
+fn lock<S>(inner: &Mutex<S>) -> MutexGuard<'_, S> {
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs `f` on a new thread, resolving to the panic payload if it panicked
+async fn run_blocking<T, F>(f: F) -> thread::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    });
+    rx.await.expect("result is always sent")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Error, ServiceOne, ServiceThree, ServiceTwo};
+    use futures::executor::LocalPool;
+
+    /// Natively async service, only starts once it is signalled
+    struct Signalled(Option<oneshot::Receiver<()>>);
+    impl AsyncStartableService for Signalled {
+        fn start(&mut self) -> BoxFuture<'_, Result<(), String>> {
+            Box::pin(async move {
+                let rx = self.0.take().ok_or("already started")?;
+                rx.await.map_err(|_| "signal dropped".to_owned())
+            })
+        }
+    }
+
+    /// Sends the signal once started
+    struct Signaller(Option<oneshot::Sender<()>>);
+    impl StartableService for Signaller {
+        fn start(&mut self) -> Result<(), String> {
+            let tx = self.0.take().ok_or("already started")?;
+            tx.send(()).map_err(|_| "receiver dropped".to_owned())
+        }
+    }
+
+    #[test]
+    fn mixed_services() {
+        let (tx, rx) = oneshot::channel();
+        let mut native = Signalled(Some(rx));
+        let mut one = Blocking::new(ServiceOne);
+        let mut two = Blocking::new(ServiceTwo);
+        let mut signaller = Blocking::new(Signaller(Some(tx)));
+
+        // Native service is first, so it can only finish if the rest are started concurrently
+        let mut services: Vec<&mut (dyn AsyncStartableService + Send)> =
+            vec![&mut native, &mut one, &mut two, &mut signaller];
+        let results = LocalPool::new().run_until(start_all_async(&mut services));
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err("Service two failed!".to_owned()),
+                Ok(())
+            ]
+        );
+    }
+
+    #[test]
+    fn assoc_services() {
+        let mut pool = LocalPool::new();
+        let mut three = Blocking::new(ServiceThree { fails: true });
+        assert_eq!(
+            pool.run_until(AsyncAssocService::start(&mut three)),
+            Err(Error::FailedToStart)
+        );
+
+        three.lock().fails = false;
+        assert_eq!(pool.run_until(AsyncAssocService::start(&mut three)), Ok(()));
+    }
+}
@@ -0,0 +1,674 @@
+use generics::{dependency_order, Error, ServiceFactory, ServiceRegistry, ServiceStatus, State};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: servicectl [--json] <config.toml> <command>...
+
+Commands run in order against the services in the config. `start` and `restart` run the
+services in a background servicectl process for the config, which later invocations for the
+same config hand their commands to, so `servicectl services.toml start` can be followed by
+`servicectl services.toml status` or `stop`. `stop` also ends the background process
+
+Commands:
+  start            start every service, after the services it depends on
+  stop             stop every running service, before the services it depends on
+  status           print state, uptime, restart count and last error of each service
+  restart <name>   stop a single service if it is running, then start it
+  graph            print the order services are started in
+
+Exit codes:
+  0  all up: every service is running, or none failed after `stop`
+  1  degraded: some services are running, others are not
+  2  failed: no service is running, or a service failed to stop
+  3  bad usage or config
+  4  down: nothing is running for the config, so there was nothing to report on or stop";
+
+const EXIT_USAGE: i32 = 3;
+
+/// Overall state of the service set, which decides the exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Overall {
+    Up = 0,
+    Degraded = 1,
+    Failed = 2,
+    /// Nothing was started for the config, so there was nothing to report on or stop
+    Down = 4,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Start,
+    Stop,
+    Status,
+    Restart(String),
+    Graph,
+}
+
+struct Options {
+    json: bool,
+    config: String,
+    commands: Vec<Command>,
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut json = false;
+    let mut config = None;
+    let mut commands = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let command = match arg.as_str() {
+            "--json" => {
+                json = true;
+                continue;
+            }
+            _ if config.is_none() => {
+                config = Some(arg.clone());
+                continue;
+            }
+            "start" => Command::Start,
+            "stop" => Command::Stop,
+            "status" => Command::Status,
+            "graph" => Command::Graph,
+            "restart" => match args.next() {
+                Some(name) => Command::Restart(name.clone()),
+                None => return Err("`restart` needs a service name".to_owned()),
+            },
+            other => return Err(format!("unknown command `{}`", other)),
+        };
+        commands.push(command);
+    }
+    let config = config.ok_or("missing config file")?;
+    if commands.is_empty() {
+        return Err("missing command".to_owned());
+    }
+    Ok(Options {
+        json,
+        config,
+        commands,
+    })
+}
+
+fn factory() -> ServiceFactory {
+    #[allow(unused_mut)]
+    let mut factory = ServiceFactory::with_builtins();
+    #[cfg(unix)]
+    factory.register("process", |p| {
+        let mut service = generics::ProcessService::new(&p.require::<String>("command")?);
+        for arg in p.get::<Vec<String>>("args")?.unwrap_or_default() {
+            service = service.arg(&arg);
+        }
+        Ok(service)
+    });
+    factory
+}
+
+/// Services registered in dependency order, with the dependencies of each
+struct ServiceSet {
+    registry: ServiceRegistry,
+    depends_on: HashMap<String, Vec<String>>,
+    /// Whether the last command was meant to leave the services running
+    expect_running: bool,
+    /// Whether any command started services
+    started: bool,
+}
+
+impl ServiceSet {
+    fn load(config: &str) -> Result<Self, Error> {
+        let mut loaded = factory().load_file(config)?;
+        let order: Vec<String> = dependency_order(
+            loaded
+                .iter()
+                .map(|s| (s.name.as_str(), s.depends_on.as_slice())),
+        )?
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+
+        let mut registry = ServiceRegistry::new();
+        let mut depends_on = HashMap::new();
+        for name in order {
+            let i = loaded.iter().position(|s| s.name == name).expect("ordered");
+            let service = loaded.swap_remove(i);
+            registry.register_boxed(&service.name, service.service)?;
+            depends_on.insert(service.name, service.depends_on);
+        }
+        Ok(Self {
+            registry,
+            depends_on,
+            expect_running: false,
+            started: false,
+        })
+    }
+
+    fn start(&mut self, err: &mut dyn Write) -> io::Result<()> {
+        self.expect_running = true;
+        self.started = true;
+        let names: Vec<String> = self.registry.names().map(str::to_owned).collect();
+        for name in names {
+            if self.registry.state(&name) == Some(&State::Running) {
+                continue;
+            }
+            let down = self.depends_on[&name]
+                .iter()
+                .find(|d| self.registry.state(d) != Some(&State::Running));
+            if let Some(dep) = down {
+                writeln!(err, "{}: not started, `{}` is not running", name, dep)?;
+                continue;
+            }
+            if let Err(e) = self.registry.start(&name) {
+                writeln!(err, "{}: {}", name, e)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, err: &mut dyn Write) -> io::Result<()> {
+        self.expect_running = false;
+        for (name, res) in self.registry.stop_all() {
+            if let Err(e) = res {
+                writeln!(err, "{}: {}", name, e)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn statuses(&self) -> Vec<ServiceStatus> {
+        self.registry
+            .names()
+            .filter_map(|n| self.registry.status(n))
+            .collect()
+    }
+
+    /// Overall state once every command has run
+    fn overall(&self) -> Overall {
+        if !self.started {
+            return Overall::Down;
+        }
+        let statuses = self.statuses();
+        if !self.expect_running {
+            let failed = statuses.iter().any(|s| matches!(s.state, State::Failed(_)));
+            return if failed { Overall::Failed } else { Overall::Up };
+        }
+        let running = statuses
+            .iter()
+            .filter(|s| s.state == State::Running)
+            .count();
+        if running == statuses.len() {
+            Overall::Up
+        } else if running > 0 {
+            Overall::Degraded
+        } else {
+            Overall::Failed
+        }
+    }
+}
+
+fn format_uptime(uptime: Option<Duration>) -> String {
+    let secs = match uptime {
+        Some(d) => d.as_secs_f64(),
+        None => return "-".to_owned(),
+    };
+    let whole = secs as u64;
+    if whole < 60 {
+        format!("{:.1}s", secs)
+    } else if whole < 3600 {
+        format!("{}m{}s", whole / 60, whole % 60)
+    } else {
+        format!("{}h{}m", whole / 3600, whole % 3600 / 60)
+    }
+}
+
+fn print_status(statuses: &[ServiceStatus], json: bool, out: &mut dyn Write) -> io::Result<()> {
+    if json {
+        let services: Vec<_> = statuses
+            .iter()
+            .map(|s| {
+                json!({
+                    "name": s.name,
+                    "state": s.state.to_string(),
+                    "uptime_secs": s.uptime.map(|d| d.as_secs_f64()),
+                    "restarts": s.restarts,
+                    "last_error": s.last_error,
+                })
+            })
+            .collect();
+        return writeln!(out, "{}", json!(services));
+    }
+
+    let header = ["NAME", "STATE", "UPTIME", "RESTARTS", "LAST ERROR"];
+    let rows: Vec<[String; 5]> = statuses
+        .iter()
+        .map(|s| {
+            [
+                s.name.clone(),
+                s.state.to_string(),
+                format_uptime(s.uptime),
+                s.restarts.to_string(),
+                s.last_error.clone().unwrap_or_else(|| "-".to_owned()),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let header = header.map(str::to_owned);
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{:w$}", cell, w = w))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+fn print_graph(set: &ServiceSet, json: bool, out: &mut dyn Write) -> io::Result<()> {
+    let names: Vec<&str> = set.registry.names().collect();
+    if json {
+        let services: Vec<_> = names
+            .iter()
+            .map(|n| json!({ "name": n, "depends_on": set.depends_on[*n] }))
+            .collect();
+        return writeln!(out, "{}", json!(services));
+    }
+    for name in names {
+        match set.depends_on[name].as_slice() {
+            [] => writeln!(out, "{}", name)?,
+            deps => writeln!(out, "{} <- {}", name, deps.join(", "))?,
+        }
+    }
+    Ok(())
+}
+
+/// Runs the command line, returning the exit code. Commands are handed to the background
+/// process for the config if one is running, or if they start services
+fn run(args: &[String], out: &mut dyn Write, err: &mut dyn Write) -> io::Result<i32> {
+    let options = match parse(args) {
+        Ok(o) => o,
+        Err(e) => return usage_error(&e, err),
+    };
+    // Loaded here even if the background process runs the commands, so a bad config is
+    // reported straight away
+    let mut set = match ServiceSet::load(&options.config) {
+        Ok(s) => s,
+        Err(e) => {
+            writeln!(err, "error: {}", e)?;
+            return Ok(EXIT_USAGE);
+        }
+    };
+    #[cfg(unix)]
+    {
+        let starts = options
+            .commands
+            .iter()
+            .any(|c| matches!(c, Command::Start | Command::Restart(_)));
+        if let Some(stream) = daemon::connect(&options.config, starts)? {
+            return daemon::forward(stream, args, out, err);
+        }
+    }
+    execute(&mut set, &options, out, err)
+}
+
+fn usage_error(e: &str, err: &mut dyn Write) -> io::Result<i32> {
+    writeln!(err, "error: {}\n\n{}", e, USAGE)?;
+    Ok(EXIT_USAGE)
+}
+
+/// Runs every command against `set`, returning the exit code
+fn execute(
+    set: &mut ServiceSet,
+    options: &Options,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> io::Result<i32> {
+    for command in &options.commands {
+        match command {
+            Command::Start => set.start(err)?,
+            Command::Stop => set.stop(err)?,
+            Command::Status => print_status(&set.statuses(), options.json, out)?,
+            Command::Graph => print_graph(set, options.json, out)?,
+            Command::Restart(name) => {
+                set.expect_running = true;
+                set.started = true;
+                if let Err(e) = set.registry.restart(name) {
+                    writeln!(err, "{}: {}", name, e)?;
+                    if let Error::UnknownService(_) = e {
+                        return Ok(EXIT_USAGE);
+                    }
+                }
+            }
+        }
+    }
+    let overall = set.overall();
+    if overall == Overall::Down {
+        writeln!(
+            err,
+            "no services are running for this config, `start` them first"
+        )?;
+    }
+    Ok(overall as i32)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    #[cfg(unix)]
+    if let [serve, config] = args.as_slice() {
+        if serve == daemon::SERVE {
+            if let Err(e) = daemon::serve(config, &daemon::socket_path(config)) {
+                eprintln!("error: {}", e);
+                process::exit(EXIT_USAGE);
+            }
+            return;
+        }
+    }
+    let code = run(&args, &mut io::stdout(), &mut io::stderr()).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        EXIT_USAGE
+    });
+    process::exit(code);
+}
+
+/// Background process which keeps the services of a config running between invocations, and
+/// runs the commands later invocations send over a unix socket
+#[cfg(unix)]
+mod daemon {
+    use super::{execute, parse, usage_error, ServiceSet};
+    use serde_json::json;
+    use std::collections::hash_map::DefaultHasher;
+    use std::env;
+    use std::fs;
+    use std::hash::{Hash, Hasher};
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// First argument which makes servicectl run as the background process for the config
+    pub const SERVE: &str = "--serve";
+
+    /// How long a new background process has to start listening
+    const SPAWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Socket of the background process for a config, the same for every path to the file
+    pub fn socket_path(config: &str) -> PathBuf {
+        let path = fs::canonicalize(config).unwrap_or_else(|_| PathBuf::from(config));
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        env::temp_dir().join(format!("servicectl-{:016x}.sock", hasher.finish()))
+    }
+
+    /// Connects to the background process for the config. If none is running, one is started
+    /// when `spawn` is set, otherwise there is nothing to connect to
+    pub fn connect(config: &str, spawn: bool) -> io::Result<Option<UnixStream>> {
+        let socket = socket_path(config);
+        if let Ok(stream) = UnixStream::connect(&socket) {
+            return Ok(Some(stream));
+        }
+        if !spawn {
+            return Ok(None);
+        }
+        // Own process group, so a signal sent to the shell's job does not reach it
+        Command::new(env::current_exe()?)
+            .args([SERVE, config])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        let deadline = Instant::now() + SPAWN_TIMEOUT;
+        loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => return Ok(Some(stream)),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    /// Sends the command line to the background process, then writes out what it printed
+    pub fn forward(
+        mut stream: UnixStream,
+        args: &[String],
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> io::Result<i32> {
+        writeln!(stream, "{}", json!(args))?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        let reply: serde_json::Value = serde_json::from_str(&reply)?;
+        out.write_all(reply["out"].as_str().unwrap_or_default().as_bytes())?;
+        err.write_all(reply["err"].as_str().unwrap_or_default().as_bytes())?;
+        Ok(reply["code"].as_i64().unwrap_or(super::EXIT_USAGE as i64) as i32)
+    }
+
+    /// Loads the config and runs commands sent to `socket`, until one stops the services
+    pub fn serve(config: &str, socket: &Path) -> io::Result<()> {
+        let mut set = ServiceSet::load(config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        // Left behind by a background process which did not exit cleanly
+        let _ = fs::remove_file(socket);
+        let listener = UnixListener::bind(socket)?;
+        for stream in listener.incoming() {
+            // * A client which goes away, or only checks the socket is there, does not take
+            // * the services down with it
+            if let Ok(false) = stream.and_then(|stream| handle(&mut set, stream)) {
+                break;
+            }
+        }
+        fs::remove_file(socket)
+    }
+
+    /// Runs the command line sent over `stream`, returning whether services are still
+    /// meant to be running
+    fn handle(set: &mut ServiceSet, mut stream: UnixStream) -> io::Result<bool> {
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let args: Vec<String> = serde_json::from_str(&request)?;
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = match parse(&args) {
+            Ok(options) => execute(set, &options, &mut out, &mut err)?,
+            Err(e) => usage_error(&e, &mut err)?,
+        };
+        let reply = json!({
+            "code": code,
+            "out": String::from_utf8_lossy(&out),
+            "err": String::from_utf8_lossy(&err),
+        });
+        writeln!(stream, "{}", reply)?;
+        Ok(set.expect_running)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    const SERVICES: &str = r#"
+[[service]]
+name = "app"
+kind = "one"
+depends_on = ["db", "flaky"]
+
+[[service]]
+name = "db"
+kind = "one"
+
+[[service]]
+name = "flaky"
+kind = "three"
+fails = true
+"#;
+
+    /// Writes `config` to a file of its own, so tests running at the same time never share one
+    fn config_file(config: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "servicectl-{}-{}.toml",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&path, config).unwrap();
+        path
+    }
+
+    fn output(out: Vec<u8>, err: Vec<u8>) -> (String, String) {
+        (
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(err).unwrap(),
+        )
+    }
+
+    /// Runs the command line as a client, which never starts a background process as long as
+    /// `args` does not start services
+    fn run_with(path: &Path, args: &[&str]) -> (i32, String, String) {
+        let mut full = vec![path.display().to_string()];
+        full.extend(args.iter().map(|a| (*a).to_owned()));
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&full, &mut out, &mut err).unwrap();
+        let (out, err) = output(out, err);
+        (code, out, err)
+    }
+
+    /// Runs the commands against services loaded in this process, as the background process
+    /// does
+    fn execute_with(config: &str, args: &[&str]) -> (i32, String, String) {
+        let path = config_file(config);
+        let mut full = vec![path.display().to_string()];
+        full.extend(args.iter().map(|a| (*a).to_owned()));
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = match parse(&full) {
+            Ok(options) => match ServiceSet::load(&options.config) {
+                Ok(mut set) => execute(&mut set, &options, &mut out, &mut err).unwrap(),
+                Err(e) => {
+                    writeln!(err, "error: {}", e).unwrap();
+                    EXIT_USAGE
+                }
+            },
+            Err(e) => {
+                writeln!(err, "error: {}", e).unwrap();
+                EXIT_USAGE
+            }
+        };
+        fs::remove_file(&path).unwrap();
+        let (out, err) = output(out, err);
+        (code, out, err)
+    }
+
+    #[test]
+    fn degraded_when_a_service_fails() {
+        let (code, out, err) = execute_with(SERVICES, &["start", "status"]);
+        assert_eq!(code, Overall::Degraded as i32);
+        assert!(err.contains("app: not started, `flaky` is not running"));
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("NAME   STATE"));
+        assert!(lines[1].starts_with("db     running"));
+        assert!(lines[2].ends_with("0         Failed to start service"));
+        assert!(!lines[2].contains("Custom Service error"));
+        assert!(lines[3].starts_with("app    created"));
+    }
+
+    #[test]
+    fn json_status_and_graph() {
+        let all_up = SERVICES.replace("fails = true", "fails = false");
+        let (code, out, _) = execute_with(
+            &all_up,
+            &["--json", "graph", "start", "restart", "db", "status"],
+        );
+        assert_eq!(code, Overall::Up as i32);
+        let mut lines = out.lines();
+        let graph: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(graph[2]["name"], "app");
+        assert_eq!(graph[2]["depends_on"], json!(["db", "flaky"]));
+        let status: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(status[0]["name"], "db");
+        assert_eq!(status[0]["state"], "running");
+        assert_eq!(status[0]["restarts"], 1);
+        assert!(status[0]["uptime_secs"].is_f64());
+        assert_eq!(status[0]["last_error"], serde_json::Value::Null);
+
+        let (code, _, _) = execute_with(&all_up, &["start", "stop"]);
+        assert_eq!(code, Overall::Up as i32);
+    }
+
+    #[test]
+    fn down_without_start() {
+        let path = config_file(&SERVICES.replace("fails = true", "fails = false"));
+        let (code, out, err) = run_with(&path, &["status"]);
+        assert_eq!(code, Overall::Down as i32);
+        assert!(out.lines().nth(1).unwrap().starts_with("db     created"));
+        assert!(err.starts_with("no services are running"));
+
+        let (code, _, _) = run_with(&path, &["stop"]);
+        assert_eq!(code, Overall::Down as i32);
+        // Printing the order checks nothing, so it does not claim everything is up
+        let (code, out, _) = run_with(&path, &["graph"]);
+        assert_eq!(code, Overall::Down as i32);
+        assert!(out.starts_with("db\n"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn later_invocations_reach_background_process() {
+        let path = config_file(&SERVICES.replace("fails = true", "fails = false"));
+        let config = path.display().to_string();
+        let socket = daemon::socket_path(&config);
+        let serving = {
+            let (config, socket) = (config.clone(), socket.clone());
+            thread::spawn(move || daemon::serve(&config, &socket).unwrap())
+        };
+        while std::os::unix::net::UnixStream::connect(&socket).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Each call is a separate invocation, which only shares the background process
+        let (code, _, _) = run_with(&path, &["start"]);
+        assert_eq!(code, Overall::Up as i32);
+        let (code, out, _) = run_with(&path, &["--json", "status"]);
+        assert_eq!(code, Overall::Up as i32);
+        let status: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(status[0]["state"], "running");
+        let (code, _, _) = run_with(&path, &["restart", "db"]);
+        assert_eq!(code, Overall::Up as i32);
+        let (code, _, _) = run_with(&path, &["stop"]);
+        assert_eq!(code, Overall::Up as i32);
+
+        // Stopping ends the background process, so nothing is running any more
+        serving.join().unwrap();
+        assert!(!socket.exists());
+        let (code, _, _) = run_with(&path, &["status"]);
+        assert_eq!(code, Overall::Down as i32);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_and_usage_errors() {
+        let (code, _, _) = execute_with(&SERVICES.replace("\"one\"", "\"two\""), &["start"]);
+        assert_eq!(code, Overall::Failed as i32);
+        let (code, _, err) = execute_with(SERVICES, &["restart"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: `restart` needs a service name"));
+        let (code, _, _) = execute_with(SERVICES, &["restart", "missing"]);
+        assert_eq!(code, EXIT_USAGE);
+        let (code, _, err) = execute_with("[[service]]\nname = 1", &["graph"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.contains(":2: "));
+        assert_eq!(format_uptime(Some(Duration::from_secs(3725))), "1h2m");
+    }
+}
@@ -0,0 +1,357 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often `recv_before` checks for a message while waiting on a simulated clock
+const RECV_POLL: Duration = Duration::from_millis(1);
+
+/// Source of time for every timing dependent feature, so tests can swap in a `SimClock`
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    /// Wall clock time, used for timestamps and file ages
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+    /// Real time left until `deadline`, for clocks which follow real time. Waits can then
+    /// block until the deadline instead of polling, so simulated clocks keep the default `None`
+    fn real_timeout(&self, _deadline: Instant) -> Option<Duration> {
+        None
+    }
+}
+
+/// Real time, the default clock everywhere
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+    fn real_timeout(&self, deadline: Instant) -> Option<Duration> {
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Shared system clock, used as the default by types that take a clock
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[derive(Debug)]
+struct SimState {
+    elapsed: Duration,
+    /// Wake up time of every thread blocked in `sleep`, only used by manual clocks
+    sleepers: Vec<Duration>,
+    sleeps: Vec<Duration>,
+}
+
+impl SimState {
+    fn waiting(&self) -> usize {
+        self.sleepers.iter().filter(|&&w| w > self.elapsed).count()
+    }
+}
+
+/// Clock which only moves when told to. Either every sleep moves time forward straight away
+/// (`new`), or sleeps block until another thread calls `advance` (`manual`)
+#[derive(Debug)]
+pub struct SimClock {
+    start: Instant,
+    start_system: SystemTime,
+    auto_advance: bool,
+    state: Mutex<SimState>,
+    changed: Condvar,
+}
+
+impl SimClock {
+    /// Clock where sleeping moves time forward by the sleep duration, without waiting
+    pub fn new() -> Self {
+        Self::with_auto_advance(true)
+    }
+
+    /// Clock where sleeps block until time is advanced past the wake up time
+    pub fn manual() -> Self {
+        Self::with_auto_advance(false)
+    }
+
+    fn with_auto_advance(auto_advance: bool) -> Self {
+        Self {
+            start: Instant::now(),
+            start_system: SystemTime::now(),
+            auto_advance,
+            state: Mutex::new(SimState {
+                elapsed: Duration::from_secs(0),
+                sleepers: Vec::new(),
+                sleeps: Vec::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Simulated time passed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.lock().elapsed += duration;
+        self.changed.notify_all();
+    }
+
+    /// Moves time forward to `elapsed`, does nothing if it has already passed
+    pub fn advance_to(&self, elapsed: Duration) {
+        let mut state = self.lock();
+        state.elapsed = state.elapsed.max(elapsed);
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    /// Every duration slept so far, in order
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.lock().sleeps.clone()
+    }
+
+    /// Blocks until `n` threads are sleeping on a manual clock with a wake up time still
+    /// ahead, so time can be advanced knowing exactly who is waiting on it
+    pub fn wait_for_sleepers(&self, n: usize) {
+        let mut state = self.lock();
+        while state.waiting() < n {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Number of threads sleeping on a manual clock with a wake up time still ahead
+    pub fn sleepers(&self) -> usize {
+        self.lock().waiting()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+    fn system_time(&self) -> SystemTime {
+        self.start_system + self.elapsed()
+    }
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.lock();
+        state.sleeps.push(duration);
+        if self.auto_advance {
+            state.elapsed += duration;
+            drop(state);
+            // Lets threads waiting on a result run before time moves on any further
+            thread::yield_now();
+            return;
+        }
+        let wake_at = state.elapsed + duration;
+        state.sleepers.push(wake_at);
+        self.changed.notify_all();
+        while state.elapsed < wake_at {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        let i = state.sleepers.iter().position(|&w| w == wake_at);
+        state.sleepers.swap_remove(i.expect("sleeper was added"));
+    }
+}
+
+/// Waits for a message until `deadline` on `clock`, like `Receiver::recv_timeout`. Blocks on
+/// the channel for real time clocks, and polls it while sleeping on simulated ones
+pub(crate) fn recv_before<T>(
+    clock: &dyn Clock,
+    rx: &Receiver<T>,
+    deadline: Instant,
+) -> Result<T, RecvTimeoutError> {
+    if let Some(timeout) = clock.real_timeout(deadline) {
+        return rx.recv_timeout(timeout);
+    }
+    loop {
+        match rx.try_recv() {
+            Ok(msg) => return Ok(msg),
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {}
+        }
+        let now = clock.now();
+        if now >= deadline {
+            return Err(RecvTimeoutError::Timeout);
+        }
+        clock.sleep((deadline - now).min(RECV_POLL));
+    }
+}
+
+type Task = Box<dyn FnOnce(&mut SimScheduler)>;
+
+/// Runs tasks in simulated time on the current thread. Tasks run in order of their due time,
+/// then the order they were scheduled in, so every run of a test sees the same sequence
+pub struct SimScheduler {
+    clock: Arc<SimClock>,
+    /// Due time (as time since the clock was created) and id of each task
+    queue: BinaryHeap<Reverse<(Duration, u64)>>,
+    tasks: HashMap<u64, Task>,
+    next_id: u64,
+}
+
+impl SimScheduler {
+    pub fn new(clock: Arc<SimClock>) -> Self {
+        Self {
+            clock,
+            queue: BinaryHeap::new(),
+            tasks: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn clock(&self) -> &Arc<SimClock> {
+        &self.clock
+    }
+
+    /// Runs `task` once `delay` has passed, tasks can schedule further tasks
+    pub fn schedule<F: FnOnce(&mut SimScheduler) + 'static>(&mut self, delay: Duration, task: F) {
+        let due = self.clock.elapsed() + delay;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push(Reverse((due, id)));
+        self.tasks.insert(id, Box::new(task));
+    }
+
+    /// Number of tasks waiting to run
+    pub fn pending(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Moves time to the next task and runs it, returns false if there is none
+    pub fn step(&mut self) -> bool {
+        let Reverse((due, id)) = match self.queue.pop() {
+            Some(next) => next,
+            None => return false,
+        };
+        self.clock.advance_to(due);
+        let task = self.tasks.remove(&id).expect("every queued id has a task");
+        task(self);
+        true
+    }
+
+    /// Runs every task due within `duration`, then moves time to the end of it. Returns the
+    /// number of tasks run
+    pub fn run_for(&mut self, duration: Duration) -> usize {
+        let end = self.clock.elapsed() + duration;
+        let mut ran = 0;
+        while matches!(self.queue.peek(), Some(Reverse((due, _))) if *due <= end) {
+            self.step();
+            ran += 1;
+        }
+        self.clock.advance_to(end);
+        ran
+    }
+
+    /// Runs tasks until none are left, returning the number run. Never returns if tasks keep
+    /// scheduling more tasks
+    pub fn run(&mut self) -> usize {
+        let mut ran = 0;
+        while self.step() {
+            ran += 1;
+        }
+        ran
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+
+    #[test]
+    fn sim_clock_only_moves_when_told() {
+        let clock = SimClock::new();
+        let start = clock.now();
+        clock.sleep(Duration::from_secs(5));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now() - start, Duration::from_secs(6));
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(5)]);
+
+        let clock = Arc::new(SimClock::manual());
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || clock.sleep(Duration::from_secs(10)))
+        };
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(9));
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(1));
+        sleeper.join().unwrap();
+    }
+
+    #[test]
+    fn recv_times_out_on_sim_clock() {
+        let clock = SimClock::new();
+        let (tx, rx) = mpsc::channel::<()>();
+        let deadline = clock.now() + Duration::from_secs(30);
+        assert_eq!(
+            recv_before(&clock, &rx, deadline),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(clock.elapsed(), Duration::from_secs(30));
+        tx.send(()).unwrap();
+        assert_eq!(recv_before(&clock, &rx, deadline), Ok(()));
+
+        // System clock blocks on the channel, so a message sent while waiting is seen straight
+        // away rather than at the next poll
+        let (tx, rx) = mpsc::channel();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            tx.send(()).unwrap();
+        });
+        let deadline = Instant::now() + Duration::from_secs(30);
+        assert_eq!(recv_before(&SystemClock, &rx, deadline), Ok(()));
+        sender.join().unwrap();
+        assert_eq!(
+            recv_before(&SystemClock, &rx, Instant::now()),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn scheduler_runs_in_time_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut sched = SimScheduler::new(Arc::new(SimClock::new()));
+        for (delay, name) in [(3, "c"), (1, "a"), (3, "d"), (2, "b")] {
+            let log = log.clone();
+            sched.schedule(Duration::from_secs(delay), move |s| {
+                log.borrow_mut().push((name, s.clock().elapsed().as_secs()));
+                if name == "a" {
+                    let log = log.clone();
+                    s.schedule(Duration::from_secs(5), move |s| {
+                        log.borrow_mut().push(("e", s.clock().elapsed().as_secs()))
+                    });
+                }
+            });
+        }
+        assert_eq!(sched.run_for(Duration::from_secs(4)), 4);
+        assert_eq!(sched.clock().elapsed(), Duration::from_secs(4));
+        assert_eq!(sched.pending(), 1);
+        assert_eq!(sched.run(), 1);
+        assert_eq!(
+            *log.borrow(),
+            vec![("a", 1), ("b", 2), ("c", 3), ("d", 3), ("e", 6)]
+        );
+    }
+}
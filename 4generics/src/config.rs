@@ -0,0 +1,432 @@
+use crate::{DynService, Error, ServiceOne, ServiceThree, ServiceTwo, StartableService};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+use toml::{Spanned, Value};
+
+type Table = BTreeMap<String, Spanned<Value>>;
+type Constructor = Box<dyn Fn(&mut Params<'_>) -> Result<Box<dyn DynService>, Error> + Send + Sync>;
+type Reconfigurer =
+    Box<dyn Fn(&mut dyn DynService, &mut Params<'_>) -> Result<(), Error> + Send + Sync>;
+
+/// Keys every service entry can have, which are not passed on to the constructor
+const RESERVED: [&str; 3] = ["name", "kind", "depends_on"];
+
+#[derive(Deserialize)]
+struct Document {
+    #[serde(default)]
+    service: Vec<Spanned<Table>>,
+}
+
+/// Position in a config document, used to point errors at the line that caused them
+#[derive(Clone, Copy)]
+struct Source<'a> {
+    file: &'a str,
+    text: &'a str,
+}
+
+impl Source<'_> {
+    fn error(&self, offset: usize, message: String) -> Error {
+        let line = self.text[..offset.min(self.text.len())]
+            .matches('\n')
+            .count()
+            + 1;
+        Error::Config {
+            file: self.file.to_owned(),
+            line,
+            message,
+        }
+    }
+}
+
+/// Parameters of a single service entry, handed to the constructor registered for its kind
+pub struct Params<'a> {
+    source: Source<'a>,
+    /// `None` while reading the reserved keys, before the kind is known
+    kind: Option<String>,
+    /// Offset of the service entry itself, for errors that are not about a single field
+    offset: usize,
+    values: Table,
+    used: BTreeSet<String>,
+}
+
+impl Params<'_> {
+    /// Value of an optional field, errors if it is present but has the wrong type
+    pub fn get<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, Error> {
+        let value = match self.values.get(key) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        self.used.insert(key.to_owned());
+        value.get_ref().clone().try_into().map(Some).map_err(|e| {
+            self.source.error(
+                value.span().start,
+                format!("bad field `{}`: {}", key, e.message()),
+            )
+        })
+    }
+
+    /// Value of a field which must be present
+    pub fn require<T: DeserializeOwned>(&mut self, key: &str) -> Result<T, Error> {
+        match self.get(key)? {
+            Some(v) => Ok(v),
+            None => Err(self.source.error(
+                self.offset,
+                format!("missing field `{}`{}", key, self.for_kind()),
+            )),
+        }
+    }
+
+    fn for_kind(&self) -> String {
+        self.kind
+            .as_ref()
+            .map_or_else(String::new, |k| format!(" for kind `{}`", k))
+    }
+
+    /// Errors for the first field the constructor did not read
+    fn finish(&self) -> Result<(), Error> {
+        match self.values.iter().find(|(k, _)| !self.used.contains(*k)) {
+            Some((k, v)) => Err(self.source.error(
+                v.span().start,
+                format!("unknown field `{}`{}", k, self.for_kind()),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Service which can take a new config entry while it runs, instead of being built again
+pub trait Reconfigure {
+    /// Applies the fields of the new entry, read the same way the constructor reads them
+    fn reconfigure(&mut self, params: &mut Params<'_>) -> Result<(), Error>;
+}
+
+impl Reconfigure for ServiceThree {
+    fn reconfigure(&mut self, params: &mut Params<'_>) -> Result<(), Error> {
+        self.fails = params.get("fails")?.unwrap_or(false);
+        Ok(())
+    }
+}
+
+/// Single service entry of a config document, before its service is built
+#[derive(Clone)]
+pub(crate) struct Entry {
+    pub(crate) name: String,
+    pub(crate) kind: String,
+    pub(crate) depends_on: Vec<String>,
+    offset: usize,
+    values: Table,
+}
+
+impl Entry {
+    /// Whether both entries build the same service, wherever they are in their documents
+    pub(crate) fn same_service(&self, other: &Entry) -> bool {
+        self.kind == other.kind
+            && self.values.len() == other.values.len()
+            && self
+                .values
+                .iter()
+                .zip(&other.values)
+                .all(|((k, v), (ok, ov))| k == ok && v.get_ref() == ov.get_ref())
+    }
+
+    fn params<'a>(&self, source: Source<'a>) -> Params<'a> {
+        Params {
+            source,
+            kind: Some(self.kind.clone()),
+            offset: self.offset,
+            values: self.values.clone(),
+            used: BTreeSet::new(),
+        }
+    }
+}
+
+/// Service built from a config entry
+pub struct LoadedService {
+    pub name: String,
+    pub kind: String,
+    pub depends_on: Vec<String>,
+    pub service: Box<dyn DynService>,
+}
+
+/// Builds services from config documents, using a constructor registered for each kind
+#[derive(Default)]
+pub struct ServiceFactory {
+    kinds: HashMap<String, Constructor>,
+    reconfigurers: HashMap<String, Reconfigurer>,
+}
+
+impl ServiceFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Factory which knows the services defined in this crate, as kinds `one`, `two` and
+    /// `three` (which takes an optional `fails` field, and can be reconfigured)
+    pub fn with_builtins() -> Self {
+        let mut factory = Self::new();
+        factory.register("one", |_| Ok(ServiceOne));
+        factory.register("two", |_| Ok(ServiceTwo));
+        factory.register_reconfigurable("three", |p| {
+            Ok(ServiceThree {
+                fails: p.get("fails")?.unwrap_or(false),
+            })
+        });
+        factory
+    }
+
+    /// Registers how to build services of the given kind, replacing any previous constructor
+    pub fn register<S, F>(&mut self, kind: &str, constructor: F)
+    where
+        S: StartableService + Send + 'static,
+        F: Fn(&mut Params<'_>) -> Result<S, Error> + Send + Sync + 'static,
+    {
+        self.reconfigurers.remove(kind);
+        self.kinds.insert(
+            kind.to_owned(),
+            Box::new(move |p| Ok(Box::new(constructor(p)?) as Box<dyn DynService>)),
+        );
+    }
+
+    /// Same as `register`, but services of this kind are reconfigured in place on reload
+    pub fn register_reconfigurable<S, F>(&mut self, kind: &str, constructor: F)
+    where
+        S: StartableService + Reconfigure + Send + 'static,
+        F: Fn(&mut Params<'_>) -> Result<S, Error> + Send + Sync + 'static,
+    {
+        self.register(kind, constructor);
+        self.reconfigurers.insert(
+            kind.to_owned(),
+            Box::new(
+                |service, p| match service.as_any_mut().downcast_mut::<S>() {
+                    Some(s) => s.reconfigure(p),
+                    None => Err(Error::Custom(format!(
+                        "service was not built as kind `{}`",
+                        p.kind.as_deref().unwrap_or_default()
+                    ))),
+                },
+            ),
+        );
+    }
+
+    /// Builds every service in a TOML document, `file` is only used for error messages.
+    ///
+    /// Each service is a `[[service]]` table with a `name`, a `kind`, an optional list of
+    /// names in `depends_on`, and any fields used by the kind's constructor.
+    pub fn load(&self, file: &str, text: &str) -> Result<Vec<LoadedService>, Error> {
+        self.parse(file, text)?
+            .into_iter()
+            .map(|entry| {
+                let service = self.build(file, text, &entry)?;
+                Ok(LoadedService {
+                    name: entry.name,
+                    kind: entry.kind,
+                    depends_on: entry.depends_on,
+                    service,
+                })
+            })
+            .collect()
+    }
+
+    /// Reads and builds every service in a TOML file
+    pub fn load_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<LoadedService>, Error> {
+        let (file, text) = read(path.as_ref())?;
+        self.load(&file, &text)
+    }
+
+    /// Reads every entry in a document without building any service, checking that names
+    /// are unique and every kind is known
+    pub(crate) fn parse(&self, file: &str, text: &str) -> Result<Vec<Entry>, Error> {
+        let source = Source { file, text };
+        let doc: Document = toml::from_str(text).map_err(|e| {
+            let offset = e.span().map_or(0, |s| s.start);
+            source.error(offset, e.message().to_owned())
+        })?;
+
+        let mut names = BTreeSet::new();
+        doc.service
+            .into_iter()
+            .map(|entry| {
+                let offset = entry.span().start;
+                let mut values = entry.into_inner();
+                let reserved: Table = RESERVED
+                    .iter()
+                    .filter_map(|k| values.remove_entry(*k))
+                    .collect();
+                let mut reserved = Params {
+                    source,
+                    kind: None,
+                    offset,
+                    values: reserved,
+                    used: BTreeSet::new(),
+                };
+                let name: String = reserved.require("name")?;
+                let kind: String = reserved.require("kind")?;
+                let depends_on = reserved.get("depends_on")?.unwrap_or_default();
+                if !names.insert(name.clone()) {
+                    return Err(source.error(offset, format!("duplicate service `{}`", name)));
+                }
+                if !self.kinds.contains_key(&kind) {
+                    let span = reserved.values["kind"].span();
+                    return Err(
+                        source.error(span.start, format!("unknown service kind `{}`", kind))
+                    );
+                }
+                Ok(Entry {
+                    name,
+                    kind,
+                    depends_on,
+                    offset,
+                    values,
+                })
+            })
+            .collect()
+    }
+
+    /// Builds the service of an entry parsed from `text`
+    pub(crate) fn build(
+        &self,
+        file: &str,
+        text: &str,
+        entry: &Entry,
+    ) -> Result<Box<dyn DynService>, Error> {
+        let constructor = &self.kinds[&entry.kind];
+        let mut params = entry.params(Source { file, text });
+        let service = constructor(&mut params)?;
+        params.finish()?;
+        Ok(service)
+    }
+
+    pub(crate) fn can_reconfigure(&self, kind: &str) -> bool {
+        self.reconfigurers.contains_key(kind)
+    }
+
+    /// Applies an entry parsed from `text` to a service of the same kind which is already built
+    pub(crate) fn reconfigure(
+        &self,
+        file: &str,
+        text: &str,
+        entry: &Entry,
+        service: &mut dyn DynService,
+    ) -> Result<(), Error> {
+        let reconfigure = self.reconfigurers.get(&entry.kind).ok_or_else(|| {
+            Error::Custom(format!("kind `{}` can not be reconfigured", entry.kind))
+        })?;
+        let mut params = entry.params(Source { file, text });
+        reconfigure(service, &mut params)?;
+        params.finish()
+    }
+}
+
+/// Reads a config file, returning its name as used in errors along with its text
+pub(crate) fn read(path: &Path) -> Result<(String, String), Error> {
+    let file = path.display().to_string();
+    let text = fs::read_to_string(path).map_err(|e| Error::Config {
+        file: file.clone(),
+        line: 0,
+        message: e.to_string(),
+    })?;
+    Ok((file, text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ServiceRegistry;
+
+    const SERVICES: &str = r#"
+[[service]]
+name = "db"
+kind = "one"
+
+[[service]]
+name = "flaky"
+kind = "three"
+depends_on = ["db"]
+fails = true
+"#;
+
+    fn load_err(text: &str) -> String {
+        match ServiceFactory::with_builtins().load("services.toml", text) {
+            Ok(_) => panic!("config should not load"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn load_services() {
+        let loaded = ServiceFactory::with_builtins()
+            .load("services.toml", SERVICES)
+            .unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].kind, "three");
+        assert_eq!(loaded[1].depends_on, vec!["db".to_owned()]);
+
+        let mut reg = ServiceRegistry::new();
+        for s in loaded {
+            reg.register_boxed(&s.name, s.service).unwrap();
+        }
+        assert_eq!(
+            reg.get::<ServiceThree>("flaky").map(|s| s.fails),
+            Some(true)
+        );
+        assert!(reg.start("flaky").is_err());
+        assert_eq!(reg.start("db"), Ok(()));
+    }
+
+    #[test]
+    fn custom_kinds() {
+        struct Named(String);
+        impl StartableService for Named {}
+
+        let mut factory = ServiceFactory::new();
+        factory.register("named", |p| Ok(Named(p.require("label")?)));
+        let loaded = factory
+            .load(
+                "t.toml",
+                "[[service]]\nname = \"n\"\nkind = \"named\"\nlabel = \"hi\"\n",
+            )
+            .unwrap();
+        assert_eq!(
+            loaded[0]
+                .service
+                .as_any()
+                .downcast_ref::<Named>()
+                .map(|n| n.0.as_str()),
+            Some("hi")
+        );
+        assert_eq!(
+            factory
+                .load("t.toml", "[[service]]\nname = \"n\"\nkind = \"named\"\n")
+                .err()
+                .map(|e| e.to_string()),
+            Some("t.toml:1: missing field `label` for kind `named`".to_owned())
+        );
+    }
+
+    #[test]
+    fn errors_point_at_line() {
+        assert_eq!(
+            load_err("[[service]]\nname = \"a\"\nkind = \"four\"\n"),
+            "services.toml:3: unknown service kind `four`"
+        );
+        assert_eq!(
+            load_err("[[service]]\nname = \"a\"\nkind = \"three\"\n\nfails = 3\n"),
+            "services.toml:5: bad field `fails`: invalid type: integer `3`, expected a boolean"
+        );
+        assert_eq!(
+            load_err("[[service]]\nname = \"a\"\nkind = \"one\"\nfails = true\n"),
+            "services.toml:4: unknown field `fails` for kind `one`"
+        );
+        assert_eq!(
+            load_err("[[service]]\nkind = \"one\"\n"),
+            "services.toml:1: missing field `name`"
+        );
+        assert_eq!(
+            load_err("[[service]]\nname = \n"),
+            "services.toml:2: invalid string\nexpected `\"`, `'`"
+        );
+    }
+}
@@ -0,0 +1,433 @@
+use crate::{dependency_order, Error, StartableService, State};
+use std::any::{self, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Type of a resource, used by services to declare what they provide and consume
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    id: TypeId,
+    name: &'static str,
+}
+
+impl Key {
+    pub fn of<T: Any>() -> Self {
+        Self {
+            id: TypeId::of::<T>(),
+            name: any::type_name::<T>(),
+        }
+    }
+
+    /// Name of the resource type, as used in errors
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key({})", self.name)
+    }
+}
+
+/// Shared resources by type, each handed out as an `Arc` to the single value provided
+#[derive(Default)]
+pub struct Resources {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a resource, replacing any previous value of the same type
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: Arc<T>) {
+        self.values.insert(TypeId::of::<T>(), value);
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        let value = self.values.get(&TypeId::of::<T>())?.clone();
+        value.downcast().ok()
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.values.contains_key(&key.id)
+    }
+
+    fn remove(&mut self, key: Key) {
+        self.values.remove(&key.id);
+    }
+
+    fn take(&mut self, key: Key) -> Option<Arc<dyn Any + Send + Sync>> {
+        self.values.remove(&key.id)
+    }
+}
+
+/// Service which takes part in a `Container`. It is handed every resource it consumes before
+/// it starts, and adds the resources it provides once it has started
+pub trait Injectable: StartableService + Send {
+    fn provides(&self) -> Vec<Key> {
+        Vec::new()
+    }
+    fn consumes(&self) -> Vec<Key> {
+        Vec::new()
+    }
+    /// Called before `start`, with every resource in `consumes` available
+    fn inject(&mut self, _resources: &Resources) -> Result<(), String> {
+        Ok(())
+    }
+    /// Called after a successful `start`, must insert every resource in `provides`. Only those
+    /// are kept, anything else inserted is dropped
+    fn provide(&self, _resources: &mut Resources) {}
+}
+
+struct Node {
+    name: String,
+    service: Box<dyn Injectable>,
+    provides: Vec<Key>,
+    /// Names of the services providing each consumed resource
+    deps: Vec<String>,
+}
+
+/// Services wired together by the resources they provide and consume. Each service is started
+/// after the providers of everything it consumes, and stopped before them
+#[derive(Default)]
+pub struct Container {
+    nodes: Vec<Node>,
+    resources: Resources,
+    /// Indexes of started services, in the order they started
+    started: Vec<usize>,
+}
+
+impl Container {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add<S: Injectable + 'static>(&mut self, name: &str, service: S) -> Result<(), Error> {
+        if self.nodes.iter().any(|n| n.name == name) {
+            return Err(Error::DuplicateService(name.to_owned()));
+        }
+        self.nodes.push(Node {
+            name: name.to_owned(),
+            provides: service.provides(),
+            service: Box::new(service),
+            deps: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Checks that every consumed resource has exactly one provider and that no service
+    /// depends on itself through its resources, returning the order services start in
+    pub fn wire(&mut self) -> Result<Vec<&str>, Error> {
+        let mut providers: HashMap<Key, Vec<&str>> = HashMap::new();
+        // Resources in the order they are first provided, so the same conflict is reported
+        // every time
+        let mut provided = Vec::new();
+        for node in &self.nodes {
+            for &key in &node.provides {
+                providers
+                    .entry(key)
+                    .or_insert_with(|| {
+                        provided.push(key);
+                        Vec::new()
+                    })
+                    .push(&node.name);
+            }
+        }
+        if let Some(key) = provided.iter().find(|k| providers[*k].len() > 1) {
+            return Err(Error::ConflictingProviders {
+                resource: key.name.to_owned(),
+                providers: providers[key].iter().map(|&n| n.to_owned()).collect(),
+            });
+        }
+        let mut deps = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let mut names: Vec<String> = Vec::new();
+            for key in node.service.consumes() {
+                let provider = providers.get(&key).ok_or_else(|| Error::MissingProvider {
+                    service: node.name.clone(),
+                    resource: key.name.to_owned(),
+                })?[0];
+                if !names.iter().any(|n| n == provider) {
+                    names.push((*provider).to_owned());
+                }
+            }
+            deps.push(names);
+        }
+        for (node, deps) in self.nodes.iter_mut().zip(deps) {
+            node.deps = deps;
+        }
+        dependency_order(
+            self.nodes
+                .iter()
+                .map(|n| (n.name.as_str(), n.deps.as_slice())),
+        )
+    }
+
+    /// Wires the services, then starts each one after its providers. All or nothing like
+    /// `start_all_transactional`: on the first failure every started service is stopped again,
+    /// and `failed` and `rollback` in the error are indexes in the order services were added.
+    /// Errors without starting anything if services are already started
+    pub fn start(&mut self) -> Result<(), Error> {
+        if !self.started.is_empty() {
+            return Err(Error::InvalidTransition {
+                from: State::Running,
+                to: State::Starting,
+            });
+        }
+        let order: Vec<String> = self.wire()?.into_iter().map(String::from).collect();
+        for name in order {
+            let i = self.index(&name);
+            if let Err(cause) = self.start_node(i) {
+                let rollback = self
+                    .stop()
+                    .into_iter()
+                    .filter_map(|(name, res)| res.err().map(|e| (self.index(&name), e)))
+                    .collect();
+                return Err(Error::StartAborted {
+                    failed: i,
+                    cause,
+                    rollback,
+                });
+            }
+            self.started.push(i);
+        }
+        Ok(())
+    }
+
+    /// Stops every started service in the reverse order they started, removing the resources
+    /// each one provided
+    pub fn stop(&mut self) -> Vec<(String, Result<(), String>)> {
+        let mut results = Vec::with_capacity(self.started.len());
+        while let Some(i) = self.started.pop() {
+            let node = &mut self.nodes[i];
+            for &key in &node.provides {
+                self.resources.remove(key);
+            }
+            results.push((node.name.clone(), node.service.stop()));
+        }
+        results
+    }
+
+    /// Resources provided by the services started so far
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    fn start_node(&mut self, i: usize) -> Result<(), String> {
+        let node = &mut self.nodes[i];
+        let resources = &mut self.resources;
+        node.service.inject(resources)?;
+        node.service.start()?;
+        // Provided into a separate set first, so a service which leaves out part of what it
+        // declares, or adds anything else, does not leave resources behind
+        let mut provided = Resources::new();
+        node.service.provide(&mut provided);
+        if let Some(key) = node.provides.iter().find(|&&k| !provided.contains(k)) {
+            // Started, so it is stopped before reporting the missing resource
+            let _ = node.service.stop();
+            return Err(format!("did not provide {}", key.name));
+        }
+        for &key in &node.provides {
+            let value = provided.take(key).expect("checked above");
+            resources.values.insert(key.id, value);
+        }
+        Ok(())
+    }
+
+    fn index(&self, name: &str) -> usize {
+        self.nodes
+            .iter()
+            .position(|n| n.name == name)
+            .expect("wired names come from the nodes")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Pool;
+    struct Cache {
+        pool: Arc<Pool>,
+    }
+
+    #[derive(Default)]
+    struct PoolService {
+        pool: Option<Arc<Pool>>,
+    }
+    impl StartableService for PoolService {
+        fn start(&mut self) -> Result<(), String> {
+            self.pool = Some(Arc::new(Pool));
+            Ok(())
+        }
+    }
+    impl Injectable for PoolService {
+        fn provides(&self) -> Vec<Key> {
+            vec![Key::of::<Pool>()]
+        }
+        fn provide(&self, resources: &mut Resources) {
+            if let Some(pool) = &self.pool {
+                resources.insert(pool.clone());
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct CacheService {
+        pool: Option<Arc<Pool>>,
+    }
+    impl StartableService for CacheService {}
+    impl Injectable for CacheService {
+        fn provides(&self) -> Vec<Key> {
+            vec![Key::of::<Cache>()]
+        }
+        fn consumes(&self) -> Vec<Key> {
+            vec![Key::of::<Pool>()]
+        }
+        fn inject(&mut self, resources: &Resources) -> Result<(), String> {
+            self.pool = resources.get();
+            Ok(())
+        }
+        fn provide(&self, resources: &mut Resources) {
+            let pool = self.pool.clone().expect("injected before start");
+            resources.insert(Arc::new(Cache { pool }));
+        }
+    }
+
+    type Handles = (Arc<Pool>, Arc<Cache>);
+
+    /// Consumes both resources, recording the handles it was given
+    struct App {
+        seen: Arc<Mutex<Option<Handles>>>,
+        fails: bool,
+    }
+    impl StartableService for App {
+        fn start(&mut self) -> Result<(), String> {
+            if self.fails {
+                return Err("app failed".to_owned());
+            }
+            Ok(())
+        }
+    }
+    impl Injectable for App {
+        fn consumes(&self) -> Vec<Key> {
+            vec![Key::of::<Cache>(), Key::of::<Pool>()]
+        }
+        fn inject(&mut self, resources: &Resources) -> Result<(), String> {
+            let pool = resources.get::<Pool>().ok_or("no pool")?;
+            let cache = resources.get::<Cache>().ok_or("no cache")?;
+            *self.seen.lock().unwrap() = Some((pool, cache));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resolves_in_dependency_order() {
+        let seen = Arc::new(Mutex::new(None));
+        let mut c = Container::new();
+        let app = App {
+            seen: seen.clone(),
+            fails: false,
+        };
+        c.add("app", app).unwrap();
+        c.add("cache", CacheService::default()).unwrap();
+        c.add("pool", PoolService::default()).unwrap();
+        assert_eq!(c.wire().unwrap(), vec!["pool", "cache", "app"]);
+
+        c.start().unwrap();
+        let (pool, cache) = seen.lock().unwrap().clone().unwrap();
+        // Every consumer shares the single provided value
+        assert!(Arc::ptr_eq(&pool, &cache.pool));
+        assert!(Arc::ptr_eq(&pool, &c.resources().get::<Pool>().unwrap()));
+
+        assert_eq!(
+            c.start(),
+            Err(Error::InvalidTransition {
+                from: State::Running,
+                to: State::Starting,
+            })
+        );
+        let stopped: Vec<_> = c.stop().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(stopped, vec!["app", "cache", "pool"]);
+        assert!(c.resources().get::<Pool>().is_none());
+    }
+
+    #[test]
+    fn wiring_errors() {
+        let mut c = Container::new();
+        c.add("cache", CacheService::default()).unwrap();
+        assert_eq!(
+            c.wire(),
+            Err(Error::MissingProvider {
+                service: "cache".to_owned(),
+                resource: Key::of::<Pool>().name().to_owned(),
+            })
+        );
+
+        c.add("pool", PoolService::default()).unwrap();
+        c.add("pool2", PoolService::default()).unwrap();
+        c.add("pool3", PoolService::default()).unwrap();
+        assert_eq!(
+            c.start(),
+            Err(Error::ConflictingProviders {
+                resource: Key::of::<Pool>().name().to_owned(),
+                providers: vec!["pool".to_owned(), "pool2".to_owned(), "pool3".to_owned()],
+            })
+        );
+        assert_eq!(
+            c.add("pool", PoolService::default()),
+            Err(Error::DuplicateService("pool".to_owned()))
+        );
+    }
+
+    #[test]
+    fn failed_start_rolls_back() {
+        /// Claims to provide a pool and a lock, but only provides the pool and something it
+        /// never declared
+        struct Lock;
+        struct Liar;
+        impl StartableService for Liar {}
+        impl Injectable for Liar {
+            fn provides(&self) -> Vec<Key> {
+                vec![Key::of::<Pool>(), Key::of::<Lock>()]
+            }
+            fn provide(&self, resources: &mut Resources) {
+                resources.insert(Arc::new(Pool));
+                resources.insert(Arc::new(7u32));
+            }
+        }
+
+        let mut c = Container::new();
+        c.add("pool", Liar).unwrap();
+        c.add("cache", CacheService::default()).unwrap();
+        assert_eq!(
+            c.start(),
+            Err(Error::StartAborted {
+                failed: 0,
+                cause: format!("did not provide {}", Key::of::<Lock>().name()),
+                rollback: vec![],
+            })
+        );
+        assert!(c.resources().get::<Pool>().is_none());
+        assert!(c.resources().get::<u32>().is_none());
+
+        let mut c = Container::new();
+        c.add("pool", PoolService::default()).unwrap();
+        c.add("cache", CacheService::default()).unwrap();
+        let app = App {
+            seen: Arc::default(),
+            fails: true,
+        };
+        c.add("app", app).unwrap();
+        let err = c.start().unwrap_err();
+        assert_eq!(err.to_string(), "Service 2 failed to start: app failed");
+        // Services started before the failure were stopped, taking their resources with them
+        assert!(c.resources().get::<Cache>().is_none());
+        assert!(c.stop().is_empty());
+    }
+}
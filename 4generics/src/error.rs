@@ -1,9 +1,111 @@
+use crate::State;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Error {
     FailedToStart,
     Custom(String),
+    RestartBudgetExceeded {
+        service: String,
+        attempts: u32,
+    },
+    DuplicateService(String),
+    UnknownService(String),
+    UnknownDependency {
+        service: String,
+        dependency: String,
+    },
+    DependencyCycle(Vec<String>),
+    InvalidTransition {
+        from: State,
+        to: State,
+    },
+    /// Service at index `failed` did not start, `rollback` holds the index and error of every
+    /// already started service which then failed to stop
+    StartAborted {
+        failed: usize,
+        cause: String,
+        rollback: Vec<(usize, String)>,
+    },
+    /// Start took longer than the given timeout
+    TimedOut(Duration),
+    /// Start was not attempted because the circuit breaker is open
+    CircuitOpen,
+    /// Every start attempt failed with a transient error, holds the last one
+    RetriesExhausted {
+        attempts: u32,
+        last: Box<Error>,
+    },
+    /// Child process exited with `code` (`None` if killed by a signal)
+    ProcessExited {
+        code: Option<i32>,
+        stderr_tail: String,
+    },
+    /// Cron or interval spec which can not be used to schedule runs
+    InvalidSchedule {
+        spec: String,
+        reason: String,
+    },
+    /// Service consumes a resource which no service provides
+    MissingProvider {
+        service: String,
+        resource: String,
+    },
+    /// More than one service provides the same resource
+    ConflictingProviders {
+        resource: String,
+        providers: Vec<String>,
+    },
+    /// Reload stopped at a step for `service`, `rollback` holds the service and error of every
+    /// step which could not be undone
+    ReloadFailed {
+        service: String,
+        cause: String,
+        rollback: Vec<(String, String)>,
+    },
+    /// Invalid config document, `line` is 0 if the error is not about any line in particular
+    Config {
+        file: String,
+        line: usize,
+        message: String,
+    },
+}
+
+impl Error {
+    /// Name of the variant, such as `TimedOut`, which failures are counted under in events and
+    /// metrics
+    pub fn variant(&self) -> &'static str {
+        match self {
+            Error::FailedToStart => "FailedToStart",
+            Error::Custom(_) => "Custom",
+            Error::RestartBudgetExceeded { .. } => "RestartBudgetExceeded",
+            Error::DuplicateService(_) => "DuplicateService",
+            Error::UnknownService(_) => "UnknownService",
+            Error::UnknownDependency { .. } => "UnknownDependency",
+            Error::DependencyCycle(_) => "DependencyCycle",
+            Error::InvalidTransition { .. } => "InvalidTransition",
+            Error::StartAborted { .. } => "StartAborted",
+            Error::TimedOut(_) => "TimedOut",
+            Error::CircuitOpen => "CircuitOpen",
+            Error::RetriesExhausted { .. } => "RetriesExhausted",
+            Error::ProcessExited { .. } => "ProcessExited",
+            Error::InvalidSchedule { .. } => "InvalidSchedule",
+            Error::MissingProvider { .. } => "MissingProvider",
+            Error::ConflictingProviders { .. } => "ConflictingProviders",
+            Error::ReloadFailed { .. } => "ReloadFailed",
+            Error::Config { .. } => "Config",
+        }
+    }
+}
+
+/// Message a service failed with. Service errors are kept as the service reported them, without
+/// the prefix `Error::Custom` adds when displayed
+pub(crate) fn message(e: &Error) -> String {
+    match e {
+        Error::Custom(msg) => msg.clone(),
+        e => e.to_string(),
+    }
 }
 
 impl fmt::Display for Error {
@@ -11,6 +113,101 @@ impl fmt::Display for Error {
         match self {
             Error::FailedToStart => write!(f, "Failed to start service"),
             Error::Custom(msg) => write!(f, "Custom Service error: {}", msg),
+            Error::RestartBudgetExceeded { service, attempts } => write!(
+                f,
+                "Service {} failed to start after {} attempts",
+                service, attempts
+            ),
+            Error::DuplicateService(name) => write!(f, "Service {} added more than once", name),
+            Error::UnknownService(name) => write!(f, "No service named {}", name),
+            Error::UnknownDependency {
+                service,
+                dependency,
+            } => write!(f, "Service {} depends on unknown {}", service, dependency),
+            Error::DependencyCycle(path) => write!(f, "Dependency cycle: {}", path.join(" -> ")),
+            Error::InvalidTransition { from, to } => {
+                write!(f, "Invalid service transition from {} to {}", from, to)
+            }
+            Error::StartAborted {
+                failed,
+                cause,
+                rollback,
+            } => {
+                write!(f, "Service {} failed to start: {}", failed, cause)?;
+                if !rollback.is_empty() {
+                    let errors: Vec<_> = rollback
+                        .iter()
+                        .map(|(i, e)| format!("service {}: {}", i, e))
+                        .collect();
+                    write!(f, " (rollback errors: {})", errors.join(", "))?;
+                }
+                Ok(())
+            }
+            Error::TimedOut(timeout) => write!(f, "Service did not start within {:?}", timeout),
+            Error::CircuitOpen => write!(f, "Circuit breaker is open"),
+            Error::RetriesExhausted { attempts, last } => {
+                write!(f, "Service failed after {} attempts: {}", attempts, last)
+            }
+            Error::ProcessExited { code, stderr_tail } => {
+                match code {
+                    Some(code) => write!(f, "Process exited with code {}", code)?,
+                    None => write!(f, "Process was killed by a signal")?,
+                }
+                if !stderr_tail.is_empty() {
+                    write!(f, ", stderr:\n{}", stderr_tail)?;
+                }
+                Ok(())
+            }
+            Error::InvalidSchedule { spec, reason } => {
+                write!(f, "Invalid schedule `{}`: {}", spec, reason)
+            }
+            Error::MissingProvider { service, resource } => write!(
+                f,
+                "Service {} consumes {} which no service provides",
+                service, resource
+            ),
+            Error::ConflictingProviders {
+                resource,
+                providers,
+            } => write!(
+                f,
+                "{} is provided by more than one service: {}",
+                resource,
+                providers.join(", ")
+            ),
+            Error::ReloadFailed {
+                service,
+                cause,
+                rollback,
+            } => {
+                write!(f, "Reload failed at service {}: {}", service, cause)?;
+                if !rollback.is_empty() {
+                    let errors: Vec<_> = rollback
+                        .iter()
+                        .map(|(s, e)| format!("service {}: {}", s, e))
+                        .collect();
+                    write!(f, " (rollback errors: {})", errors.join(", "))?;
+                }
+                Ok(())
+            }
+            Error::Config {
+                file,
+                line: 0,
+                message,
+            } => write!(f, "{}: {}", file, message),
+            Error::Config {
+                file,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", file, line, message),
         }
     }
 }
+
+impl std::error::Error for Error {}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::Custom(msg)
+    }
+}
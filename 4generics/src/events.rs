@@ -0,0 +1,279 @@
+use crate::{system_clock, Clock, Error, StartableService};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// What happened to a service
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    Starting,
+    Started,
+    /// Start or stop failed. `variant` names the `Error` variant, `Custom` for string errors
+    Failed {
+        variant: &'static str,
+        message: String,
+    },
+    /// Service is about to be started again after `delay`, `attempt` counts from 1
+    Retrying {
+        attempt: u32,
+        delay: Duration,
+    },
+    Stopping,
+    Stopped,
+}
+
+/// Single lifecycle event of a named service
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub service: String,
+    pub kind: EventKind,
+    pub at: SystemTime,
+    /// For `Started`, `Stopped` and `Failed`, how long the start or stop took
+    pub duration: Option<Duration>,
+}
+
+/// Error a start or stop can fail with, reported in a `Failed` event
+pub trait Failure: fmt::Display {
+    /// Name of the `Error` variant the failure is counted under
+    fn variant(&self) -> &'static str;
+    /// Message of the event, the displayed error by default
+    fn message(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Failure for String {
+    fn variant(&self) -> &'static str {
+        "Custom"
+    }
+}
+
+impl Failure for Error {
+    fn variant(&self) -> &'static str {
+        Error::variant(self)
+    }
+    fn message(&self) -> String {
+        crate::error::message(self)
+    }
+}
+
+type Listener = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Default)]
+struct Subscribers {
+    listeners: Vec<Listener>,
+    channels: Vec<Sender<Event>>,
+}
+
+/// Sends service events to every subscriber. Clones share the same subscribers
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Subscribers>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Arc::default(),
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock used for event times and durations
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Calls `listener` for every event. Listeners are called after the bus is unlocked, so
+    /// they can emit events and subscribe themselves
+    pub fn subscribe<F: Fn(&Event) + Send + Sync + 'static>(&self, listener: F) {
+        self.lock().listeners.push(Arc::new(listener));
+    }
+
+    /// Receives every event emitted from now on, until the receiver is dropped
+    pub fn channel(&self) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().channels.push(tx);
+        rx
+    }
+
+    pub fn emit(&self, service: &str, kind: EventKind, duration: Option<Duration>) {
+        let event = Event {
+            service: service.to_owned(),
+            kind,
+            at: self.clock.system_time(),
+            duration,
+        };
+        let listeners = {
+            let mut subs = self.lock();
+            // Channels whose receiver was dropped are removed
+            subs.channels.retain(|tx| tx.send(event.clone()).is_ok());
+            subs.listeners.clone()
+        };
+        for l in &listeners {
+            l(&event);
+        }
+    }
+
+    /// Runs `start` between `Starting` and `Started` or `Failed` events
+    pub fn observe_start<T, E, F>(&self, service: &str, start: F) -> Result<T, E>
+    where
+        E: Failure,
+        F: FnOnce() -> Result<T, E>,
+    {
+        self.observe(service, EventKind::Starting, EventKind::Started, start)
+    }
+
+    /// Runs `stop` between `Stopping` and `Stopped` or `Failed` events
+    pub fn observe_stop<T, E, F>(&self, service: &str, stop: F) -> Result<T, E>
+    where
+        E: Failure,
+        F: FnOnce() -> Result<T, E>,
+    {
+        self.observe(service, EventKind::Stopping, EventKind::Stopped, stop)
+    }
+
+    fn observe<T, E, F>(
+        &self,
+        service: &str,
+        before: EventKind,
+        after: EventKind,
+        f: F,
+    ) -> Result<T, E>
+    where
+        E: Failure,
+        F: FnOnce() -> Result<T, E>,
+    {
+        self.emit(service, before, None);
+        let started = self.clock.now();
+        let res = f();
+        let kind = match &res {
+            Ok(_) => after,
+            Err(e) => EventKind::Failed {
+                variant: e.variant(),
+                message: e.message(),
+            },
+        };
+        self.emit(service, kind, Some(self.clock.now() - started));
+        res
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Subscribers> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Same as `start_all`, but each service is named and its start is reported to `bus`
+pub fn start_all_observed(
+    services: &mut [(&str, &mut dyn StartableService)],
+    bus: &EventBus,
+) -> Vec<Result<(), String>> {
+    services
+        .iter_mut()
+        .map(|(name, s)| bus.observe_start(name, || s.start()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ServiceOne, ServiceTwo};
+
+    #[test]
+    fn events_for_each_service() {
+        let bus = EventBus::new();
+        let rx = bus.channel();
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let f = failures.clone();
+        bus.subscribe(move |e| {
+            if let EventKind::Failed { message, .. } = &e.kind {
+                f.lock().unwrap().push((e.service.clone(), message.clone()));
+            }
+        });
+
+        let mut one = ServiceOne;
+        let mut two = ServiceTwo;
+        let results = start_all_observed(&mut [("one", &mut one), ("two", &mut two)], &bus);
+        assert_eq!(results.len(), 2);
+
+        let events: Vec<_> = rx.try_iter().map(|e| (e.service, e.kind)).collect();
+        assert_eq!(
+            events,
+            vec![
+                ("one".to_owned(), EventKind::Starting),
+                ("one".to_owned(), EventKind::Started),
+                ("two".to_owned(), EventKind::Starting),
+                (
+                    "two".to_owned(),
+                    EventKind::Failed {
+                        variant: "Custom",
+                        message: "Service two failed!".to_owned()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            *failures.lock().unwrap(),
+            vec![("two".to_owned(), "Service two failed!".to_owned())]
+        );
+    }
+
+    #[test]
+    fn durations_and_dropped_channels() {
+        let bus = EventBus::new();
+        drop(bus.channel());
+        let rx = bus.channel();
+        let _ = bus.observe_stop("svc", || Ok::<_, String>(()));
+
+        let stopping = rx.recv().unwrap();
+        assert_eq!(stopping.kind, EventKind::Stopping);
+        assert_eq!(stopping.duration, None);
+        let stopped = rx.recv().unwrap();
+        assert_eq!(stopped.kind, EventKind::Stopped);
+        assert!(stopped.duration.is_some());
+        assert!(stopped.at >= stopping.at);
+        assert_eq!(bus.lock().channels.len(), 1);
+
+        // Typed errors keep their variant
+        let _ = bus.observe_start("svc", || Err::<(), _>(Error::CircuitOpen));
+        let failed = rx.try_iter().last().unwrap();
+        assert_eq!(
+            failed.kind,
+            EventKind::Failed {
+                variant: "CircuitOpen",
+                message: "Circuit breaker is open".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn listeners_can_emit() {
+        let bus = EventBus::new();
+        let rx = bus.channel();
+        let inner = bus.clone();
+        bus.subscribe(move |e| {
+            if e.kind == EventKind::Started && e.service == "db" {
+                inner.emit("cache", EventKind::Starting, None);
+            }
+        });
+        bus.emit("db", EventKind::Started, None);
+
+        let events: Vec<_> = rx.try_iter().map(|e| (e.service, e.kind)).collect();
+        assert_eq!(
+            events,
+            vec![
+                ("db".to_owned(), EventKind::Started),
+                ("cache".to_owned(), EventKind::Starting),
+            ]
+        );
+    }
+}
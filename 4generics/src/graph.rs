@@ -0,0 +1,431 @@
+use crate::parallel::panic_message;
+use crate::{Error, StartableService, State};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::thread;
+
+/// Result of starting a single service in a `ServiceGraph`
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeOutcome {
+    Started,
+    /// Start returned an error, or panicked with this message prefixed by "panicked: "
+    Failed(String),
+    /// Skipped because the named upstream service did not start
+    Blocked(String),
+    /// Not started because starting was cancelled before its turn came
+    Cancelled,
+}
+
+type Service = Box<dyn StartableService + Send>;
+
+struct Node {
+    name: String,
+    deps: Vec<String>,
+    /// `None` while starting, or once lost to a `stop_with` which did not hand it back
+    service: Option<Service>,
+}
+
+/// Set of services with named dependencies, started in dependency order
+#[derive(Default)]
+pub struct ServiceGraph {
+    nodes: Vec<Node>,
+    /// Indexes of started services, in the order they finished starting
+    started: Vec<usize>,
+}
+
+impl ServiceGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a service which will only be started once every service in `deps` has started
+    pub fn add(&mut self, name: &str, service: Box<dyn StartableService + Send>, deps: &[&str]) {
+        self.nodes.push(Node {
+            name: name.to_owned(),
+            deps: deps.iter().map(|d| (*d).to_owned()).collect(),
+            service: Some(service),
+        });
+    }
+
+    /// Names of the services in the order they would be started
+    pub fn order(&self) -> Result<Vec<&str>, Error> {
+        dependency_order(self.deps())
+    }
+
+    /// Starts every service once its dependencies have started, with independent services
+    /// started concurrently. Services which depend on one that failed are not started, and a
+    /// service which panics while starting counts as failed. Outcomes are returned in the order
+    /// the services were added. Errors without starting anything if services are already
+    /// started
+    pub fn start(&mut self) -> Result<Vec<(String, NodeOutcome)>, Error> {
+        self.start_unless(&|| false)
+    }
+
+    /// Same as `start`, but once `cancelled` returns true no other service is started, and
+    /// every service not started yet is `Cancelled`
+    pub(crate) fn start_unless(
+        &mut self,
+        cancelled: &dyn Fn() -> bool,
+    ) -> Result<Vec<(String, NodeOutcome)>, Error> {
+        if !self.started.is_empty() {
+            return Err(Error::InvalidTransition {
+                from: State::Running,
+                to: State::Starting,
+            });
+        }
+        // Validates names and checks for cycles before anything is started
+        self.order()?;
+        let index = index(&self.deps().collect::<Vec<_>>())?;
+        let deps: Vec<Vec<usize>> = self
+            .nodes
+            .iter()
+            .map(|n| n.deps.iter().map(|d| index[d.as_str()]).collect())
+            .collect();
+
+        let mut outcomes: Vec<Option<NodeOutcome>> = vec![None; self.nodes.len()];
+        let mut running = vec![false; self.nodes.len()];
+        let nodes = &mut self.nodes;
+        let started = &mut self.started;
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            let mut in_flight = 0;
+            loop {
+                for i in 0..nodes.len() {
+                    if running[i] || outcomes[i].is_some() {
+                        continue;
+                    }
+                    if cancelled() {
+                        outcomes[i] = Some(NodeOutcome::Cancelled);
+                        continue;
+                    }
+                    let blocker = deps[i].iter().find_map(|&d| match &outcomes[d] {
+                        Some(NodeOutcome::Failed(_)) => Some(nodes[d].name.clone()),
+                        Some(NodeOutcome::Blocked(root)) => Some(root.clone()),
+                        _ => None,
+                    });
+                    if let Some(root) = blocker {
+                        outcomes[i] = Some(NodeOutcome::Blocked(root));
+                        continue;
+                    }
+                    if deps[i]
+                        .iter()
+                        .all(|&d| outcomes[d] == Some(NodeOutcome::Started))
+                    {
+                        let mut service = nodes[i].service.take().expect("service started twice");
+                        let tx = tx.clone();
+                        running[i] = true;
+                        in_flight += 1;
+                        scope.spawn(move || {
+                            let res = panic::catch_unwind(AssertUnwindSafe(|| service.start()))
+                                .unwrap_or_else(|payload| {
+                                    Err(format!("panicked: {}", panic_message(&*payload)))
+                                });
+                            // Every thread reports back, even after a panic, so the receive
+                            // below never waits on a thread which is gone. Receiver outlives
+                            // every spawned thread
+                            let _ = tx.send((i, service, res));
+                        });
+                    }
+                }
+                if in_flight == 0 {
+                    break;
+                }
+                let (i, service, res) = rx.recv().expect("sender held by this scope");
+                in_flight -= 1;
+                running[i] = false;
+                nodes[i].service = Some(service);
+                outcomes[i] = Some(match res {
+                    Ok(()) => {
+                        started.push(i);
+                        NodeOutcome::Started
+                    }
+                    Err(e) => NodeOutcome::Failed(e),
+                });
+            }
+        });
+
+        Ok(self
+            .nodes
+            .iter()
+            .zip(outcomes)
+            .map(|(n, o)| (n.name.clone(), o.expect("every service has an outcome")))
+            .collect())
+    }
+
+    /// Stops every started service in the reverse order they started, so each service is
+    /// stopped before the services it depends on
+    pub fn stop(&mut self) -> Vec<(String, Result<(), String>)> {
+        self.stop_with(|_, mut service| {
+            let res = service.stop();
+            (Some(service), res)
+        })
+    }
+
+    /// Same as `stop`, but each service is handed to `stop`, which returns the result along
+    /// with the service, or `None` if the service can no longer be used
+    pub(crate) fn stop_with<R, F>(&mut self, mut stop: F) -> Vec<(String, R)>
+    where
+        F: FnMut(&str, Service) -> (Option<Service>, R),
+    {
+        let nodes = &mut self.nodes;
+        self.started
+            .drain(..)
+            .rev()
+            .map(|i| {
+                let node = &mut nodes[i];
+                let service = node.service.take().expect("service is not starting");
+                let (service, res) = stop(&node.name, service);
+                node.service = service;
+                (node.name.clone(), res)
+            })
+            .collect()
+    }
+
+    fn deps(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.nodes
+            .iter()
+            .map(|n| (n.name.as_str(), n.deps.as_slice()))
+    }
+}
+
+/// Orders named services so that each comes after all of its dependencies, otherwise keeping
+/// the order they are given in. Errors on duplicate names, unknown dependencies and cycles
+pub fn dependency_order<'a, I>(services: I) -> Result<Vec<&'a str>, Error>
+where
+    I: IntoIterator<Item = (&'a str, &'a [String])>,
+{
+    let nodes: Vec<_> = services.into_iter().collect();
+    let index = index(&nodes)?;
+    let mut visited = vec![Visit::New; nodes.len()];
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut path = Vec::new();
+    for i in 0..nodes.len() {
+        visit(&nodes, i, &index, &mut visited, &mut path, &mut order)?;
+    }
+    Ok(order.into_iter().map(|i| nodes[i].0).collect())
+}
+
+fn index<'a>(nodes: &[(&'a str, &[String])]) -> Result<HashMap<&'a str, usize>, Error> {
+    let mut index = HashMap::with_capacity(nodes.len());
+    for (i, (name, _)) in nodes.iter().enumerate() {
+        if index.insert(*name, i).is_some() {
+            return Err(Error::DuplicateService((*name).to_owned()));
+        }
+    }
+    for (name, deps) in nodes {
+        if let Some(d) = deps.iter().find(|d| !index.contains_key(d.as_str())) {
+            return Err(Error::UnknownDependency {
+                service: (*name).to_owned(),
+                dependency: d.clone(),
+            });
+        }
+    }
+    Ok(index)
+}
+
+/// Depth first visit, pushing nodes to `order` after all of their dependencies
+fn visit(
+    nodes: &[(&str, &[String])],
+    i: usize,
+    index: &HashMap<&str, usize>,
+    visited: &mut [Visit],
+    path: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) -> Result<(), Error> {
+    match visited[i] {
+        Visit::Done => return Ok(()),
+        Visit::InProgress => {
+            // Node is already on the path, so the path from it back to itself is a cycle
+            let start = path.iter().position(|&p| p == i).unwrap_or(0);
+            let cycle = path[start..]
+                .iter()
+                .chain(std::iter::once(&i))
+                .map(|&p| nodes[p].0.to_owned())
+                .collect();
+            return Err(Error::DependencyCycle(cycle));
+        }
+        Visit::New => {}
+    }
+    visited[i] = Visit::InProgress;
+    path.push(i);
+    for d in nodes[i].1 {
+        visit(nodes, index[d.as_str()], index, visited, path, order)?;
+    }
+    path.pop();
+    visited[i] = Visit::Done;
+    order.push(i);
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    InProgress,
+    Done,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ServiceOne, ServiceThree, ServiceTwo};
+    use std::sync::{Arc, Barrier};
+
+    /// Only starts once the other services sharing the barrier are starting at the same time
+    struct Rendezvous(Arc<Barrier>);
+    impl StartableService for Rendezvous {
+        fn start(&mut self) -> Result<(), String> {
+            self.0.wait();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn start_in_dependency_order() {
+        let mut graph = ServiceGraph::new();
+        graph.add("app", Box::new(ServiceOne), &["db", "cache"]);
+        graph.add("cache", Box::new(ServiceOne), &["db"]);
+        graph.add("db", Box::new(ServiceThree { fails: false }), &[]);
+        assert_eq!(graph.order(), Ok(vec!["db", "cache", "app"]));
+        assert_eq!(
+            graph.start(),
+            Ok(vec![
+                ("app".to_owned(), NodeOutcome::Started),
+                ("cache".to_owned(), NodeOutcome::Started),
+                ("db".to_owned(), NodeOutcome::Started),
+            ])
+        );
+    }
+
+    #[test]
+    fn independent_services_start_concurrently() {
+        // Would deadlock if the two leaves were started one after another
+        let barrier = Arc::new(Barrier::new(2));
+        let mut graph = ServiceGraph::new();
+        graph.add("root", Box::new(ServiceOne), &[]);
+        graph.add("left", Box::new(Rendezvous(barrier.clone())), &["root"]);
+        graph.add("right", Box::new(Rendezvous(barrier)), &["root"]);
+        let outcomes = graph.start().unwrap();
+        assert!(outcomes.iter().all(|(_, o)| *o == NodeOutcome::Started));
+
+        let stopped: Vec<_> = graph.stop().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(stopped.last().map(String::as_str), Some("root"));
+        assert_eq!(stopped.len(), 3);
+    }
+
+    #[test]
+    fn dependents_of_failed_service_are_blocked() {
+        let mut graph = ServiceGraph::new();
+        graph.add("two", Box::new(ServiceTwo), &[]);
+        graph.add("child", Box::new(ServiceOne), &["two"]);
+        graph.add("grandchild", Box::new(ServiceOne), &["child"]);
+        graph.add("other", Box::new(ServiceOne), &[]);
+        assert_eq!(
+            graph.start(),
+            Ok(vec![
+                (
+                    "two".to_owned(),
+                    NodeOutcome::Failed("Service two failed!".to_owned())
+                ),
+                ("child".to_owned(), NodeOutcome::Blocked("two".to_owned())),
+                (
+                    "grandchild".to_owned(),
+                    NodeOutcome::Blocked("two".to_owned())
+                ),
+                ("other".to_owned(), NodeOutcome::Started),
+            ])
+        );
+    }
+
+    #[test]
+    fn panicking_service_fails() {
+        struct Panics;
+        impl StartableService for Panics {
+            fn start(&mut self) -> Result<(), String> {
+                panic!("service exploded")
+            }
+        }
+
+        let mut graph = ServiceGraph::new();
+        graph.add("panics", Box::new(Panics), &[]);
+        graph.add("child", Box::new(ServiceOne), &["panics"]);
+        graph.add("other", Box::new(ServiceOne), &[]);
+        assert_eq!(
+            graph.start(),
+            Ok(vec![
+                (
+                    "panics".to_owned(),
+                    NodeOutcome::Failed("panicked: service exploded".to_owned())
+                ),
+                (
+                    "child".to_owned(),
+                    NodeOutcome::Blocked("panics".to_owned())
+                ),
+                ("other".to_owned(), NodeOutcome::Started),
+            ])
+        );
+        // Service which panicked is kept, but only the started service is stopped
+        assert!(graph.nodes[0].service.is_some());
+        let stopped: Vec<_> = graph.stop().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(stopped, vec!["other"]);
+    }
+
+    #[test]
+    fn start_twice() {
+        let mut graph = ServiceGraph::new();
+        graph.add("one", Box::new(ServiceOne), &[]);
+        graph.add("two", Box::new(ServiceOne), &["one"]);
+        assert!(graph.start().is_ok());
+        assert_eq!(
+            graph.start(),
+            Err(Error::InvalidTransition {
+                from: State::Running,
+                to: State::Starting,
+            })
+        );
+
+        // Can be started again once everything has been stopped
+        assert_eq!(graph.stop().len(), 2);
+        assert_eq!(
+            graph.start(),
+            Ok(vec![
+                ("one".to_owned(), NodeOutcome::Started),
+                ("two".to_owned(), NodeOutcome::Started),
+            ])
+        );
+    }
+
+    #[test]
+    fn invalid_graphs() {
+        let mut graph = ServiceGraph::new();
+        graph.add("a", Box::new(ServiceOne), &["b"]);
+        graph.add("b", Box::new(ServiceOne), &["c"]);
+        graph.add("c", Box::new(ServiceOne), &["a"]);
+        let err = graph.start().unwrap_err();
+        assert_eq!(
+            err,
+            Error::DependencyCycle(vec![
+                "a".to_owned(),
+                "b".to_owned(),
+                "c".to_owned(),
+                "a".to_owned()
+            ])
+        );
+        assert_eq!(err.to_string(), "Dependency cycle: a -> b -> c -> a");
+
+        let mut graph = ServiceGraph::new();
+        graph.add("a", Box::new(ServiceOne), &["missing"]);
+        assert_eq!(
+            graph.order(),
+            Err(Error::UnknownDependency {
+                service: "a".to_owned(),
+                dependency: "missing".to_owned()
+            })
+        );
+
+        let mut graph = ServiceGraph::new();
+        graph.add("a", Box::new(ServiceOne), &[]);
+        graph.add("a", Box::new(ServiceOne), &[]);
+        assert_eq!(graph.order(), Err(Error::DuplicateService("a".to_owned())));
+    }
+}
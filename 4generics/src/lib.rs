@@ -1,14 +1,74 @@
+mod adapters;
+mod async_service;
+mod clock;
+mod config;
+mod container;
 mod error;
+mod events;
+mod graph;
+mod lifecycle;
+mod metrics;
+mod middleware;
+mod parallel;
+mod probe;
+#[cfg(unix)]
+mod process;
+mod registry;
+mod reload;
+mod schedule;
 mod services;
+mod shutdown;
+mod supervisor;
 
+pub use adapters::{AsAssoc, AsStartable};
+pub use async_service::{
+    start_all_async, start_service_async, AsyncAssocService, AsyncStartableService, Blocking,
+};
+pub use clock::{system_clock, Clock, SimClock, SimScheduler, SystemClock};
+pub use config::{LoadedService, Params, Reconfigure, ServiceFactory};
+pub use container::{Container, Injectable, Key, Resources};
 pub use error::Error;
+pub use events::{start_all_observed, Event, EventBus, EventKind, Failure};
+pub use graph::{dependency_order, NodeOutcome, ServiceGraph};
+pub use lifecycle::{Health, Managed, State};
+pub use metrics::{Histogram, Metrics, MetricsServer, ServiceMetrics, DEFAULT_BUCKETS};
+pub use middleware::{
+    CircuitBreaker, CircuitBreakerLayer, CircuitState, Identity, Layer, Retry, RetryLayer,
+    ServiceBuilder, Stack, Timeout, TimeoutLayer,
+};
+pub use parallel::{start_all_parallel, Completed, ParallelOptions, StartOutcome};
+pub use probe::{Check, Probe};
+#[cfg(unix)]
+pub use process::{ProcessService, Readiness};
+pub use registry::{DynService, ServiceRegistry, ServiceStatus};
+pub use reload::{ConfiguredServices, ReloadSummary};
+pub use schedule::{
+    CatchUp, Cron, Interval, Overlap, RunOutcome, RunRecord, Schedule, ScheduledService,
+};
 pub use services::*;
+pub use shutdown::{CancellationToken, ShutdownCoordinator, StopOutcome};
+pub use supervisor::{Backoff, ChildStatus, RestartPolicy, Strategy, Supervisor};
 
 /// Basic trait which uses mutable reference to start it
 pub trait StartableService {
     fn start(&mut self) -> Result<(), String> {
         Ok(())
     }
+    fn stop(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+    /// Same as `start`, but keeping the typed error of services which have one, so failures
+    /// can be reported by variant. String errors become `Error::Custom`
+    fn start_typed(&mut self) -> Result<(), Error> {
+        self.start().map_err(Error::Custom)
+    }
+    /// Same as `stop`, but keeping the typed error of services which have one
+    fn stop_typed(&mut self) -> Result<(), Error> {
+        self.stop().map_err(Error::Custom)
+    }
 }
 /// Trait with associated type which is used here as the Error type
 pub trait AssocService {
@@ -17,6 +77,35 @@ pub trait AssocService {
     fn start(&mut self) -> Result<(), Self::AssocError> {
         Ok(())
     }
+    fn stop(&mut self) -> Result<(), Self::AssocError> {
+        Ok(())
+    }
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+}
+
+/// Error type which keeps the concrete error, so callers can still downcast it
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Object safe version of `AssocService`, implemented for every service whose error can be
+/// boxed. This allows services with different error types to be used together without
+/// flattening their errors into strings
+pub trait DynAssocService {
+    fn start_boxed(&mut self) -> Result<(), BoxError>;
+    fn stop_boxed(&mut self) -> Result<(), BoxError>;
+}
+
+impl<S: AssocService> DynAssocService for S
+where
+    S::AssocError: Into<BoxError>,
+{
+    fn start_boxed(&mut self) -> Result<(), BoxError> {
+        self.start().map_err(Into::into)
+    }
+    fn stop_boxed(&mut self) -> Result<(), BoxError> {
+        self.stop().map_err(Into::into)
+    }
 }
 
 /// Generics can be used inline or in where, but where is more extensible and usable for
@@ -41,6 +130,41 @@ pub fn start_all(services: &mut [&mut dyn StartableService]) -> Vec<Result<(), S
     services.iter_mut().map(|s| s.start()).collect()
 }
 
+/// Same as `start_all`, but keeps the typed error of each service
+pub fn start_all_typed(services: &mut [&mut dyn DynAssocService]) -> Vec<Result<(), BoxError>> {
+    services.iter_mut().map(|s| s.start_boxed()).collect()
+}
+
+/// All or nothing version of `start_all`. Stops at the first service that fails to start, then
+/// stops every service already started in reverse order and reports both the failure and any
+/// errors from stopping
+pub fn start_all_transactional(services: &mut [&mut dyn StartableService]) -> Result<(), Error> {
+    for i in 0..services.len() {
+        if let Err(cause) = services[i].start() {
+            let rollback = services[..i]
+                .iter_mut()
+                .enumerate()
+                .rev()
+                .filter_map(|(j, s)| s.stop().err().map(|e| (j, e)))
+                .collect();
+            return Err(Error::StartAborted {
+                failed: i,
+                cause,
+                rollback,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Stops services in the reverse order they were started by `start_all`, results are still in
+/// the same order as `services`
+pub fn stop_all(services: &mut [&mut dyn StartableService]) -> Vec<Result<(), String>> {
+    let mut results: Vec<_> = services.iter_mut().rev().map(|s| s.stop()).collect();
+    results.reverse();
+    results
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -83,6 +207,90 @@ mod test {
         assert_eq!(errors.len(), 2);
     }
 
+    #[test]
+    fn start_all_typed_errors() {
+        let mut s1 = ServiceOne;
+        let mut s2 = ServiceTwo;
+        let mut s3_f = ServiceThree { fails: true };
+
+        // ServiceOne uses String errors while the others use Error, boxing allows both
+        let mut services: Vec<&mut dyn DynAssocService> = vec![&mut s1, &mut s2, &mut s3_f];
+        let results = start_all_typed(&mut services);
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::Custom("Service two failed!".to_owned()))
+        );
+        let err = results[2].as_ref().unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::FailedToStart));
+    }
+
+    #[test]
+    fn transactional_rollback() {
+        use std::cell::RefCell;
+
+        struct Logged<'a>(u8, &'a RefCell<Vec<u8>>, bool);
+        impl StartableService for Logged<'_> {
+            fn stop(&mut self) -> Result<(), String> {
+                self.1.borrow_mut().push(self.0);
+                if self.2 {
+                    Err(format!("{} did not stop", self.0))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let log = RefCell::new(Vec::new());
+        let mut first = Logged(1, &log, false);
+        let mut second = Logged(2, &log, true);
+        let mut s3_f = ServiceThree { fails: true };
+        let mut last = Logged(4, &log, false);
+        let mut services: Vec<&mut dyn StartableService> =
+            vec![&mut first, &mut second, &mut s3_f, &mut last];
+        let err = start_all_transactional(&mut services).unwrap_err();
+        assert_eq!(
+            err,
+            Error::StartAborted {
+                failed: 2,
+                cause: "Failed to start service".to_owned(),
+                rollback: vec![(1, "2 did not stop".to_owned())],
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Service 2 failed to start: Failed to start service (rollback errors: service 1: 2 did not stop)"
+        );
+        // Service after the failed one is never started, so it is not stopped either
+        assert_eq!(*log.borrow(), vec![2, 1]);
+
+        let mut s1 = ServiceOne;
+        let mut services: Vec<&mut dyn StartableService> = vec![&mut s1];
+        assert_eq!(start_all_transactional(&mut services), Ok(()));
+    }
+
+    #[test]
+    fn stop_all_in_reverse() {
+        use std::cell::RefCell;
+
+        struct Logged<'a>(u8, &'a RefCell<Vec<u8>>);
+        impl StartableService for Logged<'_> {
+            fn stop(&mut self) -> Result<(), String> {
+                self.1.borrow_mut().push(self.0);
+                Ok(())
+            }
+        }
+
+        let log = RefCell::new(Vec::new());
+        let mut first = Logged(1, &log);
+        let mut second = Logged(2, &log);
+        let mut services: Vec<&mut dyn StartableService> = vec![&mut first, &mut second];
+        start_all(&mut services);
+        assert_eq!(stop_all(&mut services), vec![Ok(()), Ok(())]);
+        assert_eq!(*log.borrow(), vec![2, 1]);
+    }
+
     #[test]
     fn call_all_variants() {
         let mut s = ServiceThree { fails: true };
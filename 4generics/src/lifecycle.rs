@@ -0,0 +1,174 @@
+use crate::error::message;
+use crate::{Error, StartableService};
+use std::fmt;
+
+/// Result of a service health check
+#[derive(Debug, Clone, PartialEq)]
+pub enum Health {
+    Healthy,
+    Unhealthy(String),
+}
+
+/// Lifecycle state of a `Managed` service
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    Created,
+    Starting,
+    Running,
+    Stopping,
+    Stopped,
+    /// Start or stop returned an error, holds the error message
+    Failed(String),
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Created => write!(f, "created"),
+            State::Starting => write!(f, "starting"),
+            State::Running => write!(f, "running"),
+            State::Stopping => write!(f, "stopping"),
+            State::Stopped => write!(f, "stopped"),
+            State::Failed(e) => write!(f, "failed ({})", e),
+        }
+    }
+}
+
+/// Wraps a service to track its `State` and reject invalid transitions, such as stopping a
+/// service which was never started or starting one twice
+pub struct Managed<S: ?Sized> {
+    state: State,
+    service: Box<S>,
+}
+
+impl<S: StartableService + ?Sized> Managed<S> {
+    pub fn new(service: Box<S>) -> Self {
+        Self {
+            state: State::Created,
+            service,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn service(&self) -> &S {
+        &self.service
+    }
+
+    pub fn service_mut(&mut self) -> &mut S {
+        &mut self.service
+    }
+
+    pub fn into_inner(self) -> Box<S> {
+        self.service
+    }
+
+    /// Starts the service, only valid if it has not been started yet or has since stopped
+    pub fn start(&mut self) -> Result<(), Error> {
+        match self.state {
+            State::Created | State::Stopped | State::Failed(_) => {}
+            _ => return Err(self.invalid(State::Starting)),
+        }
+        self.state = State::Starting;
+        self.transition(|s| s.start_typed(), State::Running)
+    }
+
+    /// Stops the service, only valid while it is running
+    pub fn stop(&mut self) -> Result<(), Error> {
+        if self.state != State::Running {
+            return Err(self.invalid(State::Stopping));
+        }
+        self.state = State::Stopping;
+        self.transition(|s| s.stop_typed(), State::Stopped)
+    }
+
+    /// Health of the service, which is only checked while it is running
+    pub fn health(&self) -> Health {
+        match &self.state {
+            State::Running => self.service.health(),
+            state => Health::Unhealthy(format!("service is {}", state)),
+        }
+    }
+
+    fn transition<F>(&mut self, f: F, to: State) -> Result<(), Error>
+    where
+        F: FnOnce(&mut S) -> Result<(), Error>,
+    {
+        match f(&mut self.service) {
+            Ok(()) => {
+                self.state = to;
+                Ok(())
+            }
+            Err(e) => {
+                self.state = State::Failed(message(&e));
+                Err(e)
+            }
+        }
+    }
+
+    fn invalid(&self, to: State) -> Error {
+        Error::InvalidTransition {
+            from: self.state.clone(),
+            to,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AssocService, ServiceOne, ServiceThree, ServiceTwo};
+
+    #[test]
+    fn state_transitions() {
+        let mut s = Managed::new(Box::new(ServiceOne));
+        assert_eq!(s.state(), &State::Created);
+        assert_eq!(
+            s.stop(),
+            Err(Error::InvalidTransition {
+                from: State::Created,
+                to: State::Stopping
+            })
+        );
+
+        assert_eq!(s.start(), Ok(()));
+        assert_eq!(s.state(), &State::Running);
+        assert_eq!(s.health(), Health::Healthy);
+        assert_eq!(
+            s.start(),
+            Err(Error::InvalidTransition {
+                from: State::Running,
+                to: State::Starting
+            })
+        );
+
+        assert_eq!(s.stop(), Ok(()));
+        assert_eq!(s.state(), &State::Stopped);
+        assert_eq!(
+            s.health(),
+            Health::Unhealthy("service is stopped".to_owned())
+        );
+        // Stopped services can be started again
+        assert_eq!(s.start(), Ok(()));
+    }
+
+    #[test]
+    fn failed_start() {
+        let mut s: Managed<dyn StartableService> = Managed::new(Box::new(ServiceTwo));
+        assert_eq!(
+            s.start(),
+            Err(Error::Custom("Service two failed!".to_owned()))
+        );
+        assert_eq!(s.state(), &State::Failed("Service two failed!".to_owned()));
+    }
+
+    #[test]
+    fn default_lifecycle_methods() {
+        let mut s = ServiceThree { fails: false };
+        assert_eq!(StartableService::stop(&mut s), Ok(()));
+        assert_eq!(AssocService::stop(&mut s), Ok(()));
+        assert_eq!(StartableService::health(&s), Health::Healthy);
+    }
+}
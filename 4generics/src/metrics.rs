@@ -0,0 +1,530 @@
+use crate::error::message;
+use crate::{system_clock, Clock, Error, Event, EventBus, EventKind, State};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the start latency buckets, same as the Prometheus client defaults
+pub const DEFAULT_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long the metrics endpoint waits on a slow client before giving up on it
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Durations counted into buckets by upper bound, as in a Prometheus histogram
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// Count per bucket, with one more at the end for durations above every bound
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Histogram with the given upper bounds in seconds, which are sorted if they are not
+    pub fn new(mut bounds: Vec<f64>) -> Self {
+        bounds.sort_by(|a, b| a.total_cmp(b));
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let i = self.bounds.iter().position(|&b| secs <= b);
+        self.counts[i.unwrap_or(self.bounds.len())] += 1;
+        self.sum += secs;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum of every observed duration in seconds
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Upper bound of each bucket along with the number of durations at or below it
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(&b, &c)| {
+                total += c;
+                (b, total)
+            })
+            .collect()
+    }
+}
+
+/// Counters and gauges of a single service
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceMetrics {
+    pub start_attempts: u64,
+    /// Failed starts keyed by the name of the error variant, such as `FailedToStart`
+    pub failures: BTreeMap<String, u64>,
+    /// Number of start attempts after the first one
+    pub restarts: u64,
+    pub state: State,
+    /// Time taken by every start attempt, including those which failed
+    pub start_latency: Histogram,
+}
+
+impl ServiceMetrics {
+    fn new(buckets: &[f64]) -> Self {
+        Self {
+            start_attempts: 0,
+            failures: BTreeMap::new(),
+            restarts: 0,
+            state: State::Created,
+            start_latency: Histogram::new(buckets.to_vec()),
+        }
+    }
+}
+
+/// Per service metrics, fed either by subscribing to an `EventBus` or by starting services
+/// through `observe_start`. Clones share the same metrics
+#[derive(Clone)]
+pub struct Metrics {
+    services: Arc<Mutex<BTreeMap<String, ServiceMetrics>>>,
+    buckets: Arc<Vec<f64>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            services: Arc::default(),
+            buckets: Arc::new(DEFAULT_BUCKETS.to_vec()),
+            clock: system_clock(),
+        }
+    }
+
+    /// Upper bounds in seconds of the start latency buckets, for services not yet seen
+    pub fn with_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.buckets = Arc::new(buckets);
+        self
+    }
+
+    /// Clock `observe_start` measures latency with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Records every start and stop reported to `bus`, so services run by a `Supervisor` or
+    /// `ServiceRegistry` using the bus are measured without any further changes
+    pub fn attach(&self, bus: &EventBus) {
+        let metrics = self.clone();
+        bus.subscribe(move |e| metrics.record(e));
+    }
+
+    /// Runs `start` and records it, keeping the variant of a typed error
+    pub fn observe_start<T, F>(&self, service: &str, start: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        self.starting(service);
+        let started = self.clock.now();
+        let res = start();
+        let err = res.as_ref().err().map(|e| (e.variant(), message(e)));
+        self.finished(service, self.clock.now() - started, err);
+        res
+    }
+
+    pub fn set_state(&self, service: &str, state: State) {
+        self.entry(service, |m| m.state = state);
+    }
+
+    /// Snapshot of a single service, `None` if nothing was recorded for it
+    pub fn service(&self, name: &str) -> Option<ServiceMetrics> {
+        self.lock().get(name).cloned()
+    }
+
+    /// Snapshot of every service, keyed by name
+    pub fn services(&self) -> BTreeMap<String, ServiceMetrics> {
+        self.lock().clone()
+    }
+
+    /// Every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let services = self.lock();
+        let mut out = String::new();
+        let labelled: Vec<_> = services.iter().map(|(n, m)| (escape(n), m)).collect();
+
+        header(
+            &mut out,
+            "service_start_attempts_total",
+            "counter",
+            "Number of times each service was started",
+        );
+        for (s, m) in &labelled {
+            out += &format!(
+                "service_start_attempts_total{{service=\"{}\"}} {}\n",
+                s, m.start_attempts
+            );
+        }
+        header(
+            &mut out,
+            "service_start_failures_total",
+            "counter",
+            "Number of failed starts by error variant",
+        );
+        for (s, m) in &labelled {
+            for (error, n) in &m.failures {
+                out += &format!(
+                    "service_start_failures_total{{service=\"{}\",error=\"{}\"}} {}\n",
+                    s,
+                    escape(error),
+                    n
+                );
+            }
+        }
+        header(
+            &mut out,
+            "service_restarts_total",
+            "counter",
+            "Number of start attempts after the first one",
+        );
+        for (s, m) in &labelled {
+            out += &format!(
+                "service_restarts_total{{service=\"{}\"}} {}\n",
+                s, m.restarts
+            );
+        }
+        header(
+            &mut out,
+            "service_state",
+            "gauge",
+            "Current lifecycle state, 1 for the state the service is in",
+        );
+        for (s, m) in &labelled {
+            let current = state_name(&m.state);
+            for state in &STATES {
+                out += &format!(
+                    "service_state{{service=\"{}\",state=\"{}\"}} {}\n",
+                    s,
+                    state,
+                    (*state == current) as u8
+                );
+            }
+        }
+        header(
+            &mut out,
+            "service_start_duration_seconds",
+            "histogram",
+            "Time taken by each start attempt",
+        );
+        for (s, m) in &labelled {
+            let name = "service_start_duration_seconds";
+            let h = &m.start_latency;
+            for (bound, n) in h.buckets() {
+                out += &format!(
+                    "{}_bucket{{service=\"{}\",le=\"{}\"}} {}\n",
+                    name, s, bound, n
+                );
+            }
+            out += &format!(
+                "{}_bucket{{service=\"{}\",le=\"+Inf\"}} {}\n",
+                name,
+                s,
+                h.count()
+            );
+            out += &format!("{}_sum{{service=\"{}\"}} {}\n", name, s, h.sum());
+            out += &format!("{}_count{{service=\"{}\"}} {}\n", name, s, h.count());
+        }
+        out
+    }
+
+    /// Serves `render` at `GET /metrics` on localhost, on `port` or any free port if it is 0.
+    /// The server stops when the returned handle is dropped
+    pub fn serve(&self, port: u16) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let metrics = self.clone();
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                // A client which goes away mid request only affects itself
+                if let Ok(stream) = stream {
+                    let _ = respond(&metrics, stream);
+                }
+            }
+        });
+        Ok(MetricsServer {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    fn record(&self, event: &Event) {
+        let service = &event.service;
+        let duration = event.duration.unwrap_or_default();
+        match &event.kind {
+            EventKind::Starting => self.starting(service),
+            EventKind::Started => self.finished(service, duration, None),
+            EventKind::Failed { variant, message } if self.is_starting(service) => {
+                self.finished(service, duration, Some((variant, message.clone())))
+            }
+            EventKind::Failed { message, .. } => {
+                self.set_state(service, State::Failed(message.clone()))
+            }
+            EventKind::Stopping => self.set_state(service, State::Stopping),
+            EventKind::Stopped => self.set_state(service, State::Stopped),
+            EventKind::Retrying { .. } => {}
+        }
+    }
+
+    /// Whether a start of the service is under way, so a failure is a failed start
+    fn is_starting(&self, service: &str) -> bool {
+        self.lock()
+            .get(service)
+            .is_some_and(|m| m.state == State::Starting)
+    }
+
+    fn starting(&self, service: &str) {
+        self.entry(service, |m| {
+            if m.start_attempts > 0 {
+                m.restarts += 1;
+            }
+            m.start_attempts += 1;
+            m.state = State::Starting;
+        });
+    }
+
+    /// Records the end of a start attempt, `err` holds the variant name and message on failure
+    fn finished(&self, service: &str, duration: Duration, err: Option<(&str, String)>) {
+        self.entry(service, |m| {
+            m.start_latency.observe(duration);
+            m.state = match err {
+                Some((variant, message)) => {
+                    *m.failures.entry(variant.to_owned()).or_insert(0) += 1;
+                    State::Failed(message)
+                }
+                None => State::Running,
+            };
+        });
+    }
+
+    fn entry<F: FnOnce(&mut ServiceMetrics)>(&self, service: &str, f: F) {
+        let mut services = self.lock();
+        let m = services
+            .entry(service.to_owned())
+            .or_insert_with(|| ServiceMetrics::new(&self.buckets));
+        f(m)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, ServiceMetrics>> {
+        self.services.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle to the endpoint started by `Metrics::serve`
+pub struct MetricsServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes the server thread up from `accept` so it sees the stop flag
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Every `State` as used in the `state` label, in lifecycle order
+const STATES: [&str; 6] = [
+    "created", "starting", "running", "stopping", "stopped", "failed",
+];
+
+fn state_name(state: &State) -> &'static str {
+    match state {
+        State::Created => "created",
+        State::Starting => "starting",
+        State::Running => "running",
+        State::Stopping => "stopping",
+        State::Stopped => "stopped",
+        State::Failed(_) => "failed",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    *out += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+}
+
+/// Escapes a label value as required by the exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers a single request, then closes the connection
+fn respond(metrics: &Metrics, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are read so the client is not reset while still sending them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        AsStartable, CircuitBreaker, Fault, FaultyService, RestartPolicy, ServiceOne, Strategy,
+        Supervisor,
+    };
+    use std::io::Read;
+
+    #[test]
+    fn counts_starts_from_events() {
+        let bus = EventBus::new();
+        let metrics = Metrics::new();
+        metrics.attach(&bus);
+
+        let mut sup = Supervisor::new(Strategy::OneForOne).with_events(bus.clone());
+        sup.add(
+            "flaky",
+            Box::new(FaultyService::new(Fault::FailFirst(2))),
+            RestartPolicy::OnFailure { max_attempts: 3 },
+        );
+        sup.add("one", Box::new(ServiceOne), RestartPolicy::Never);
+        sup.start().unwrap();
+        let _ = bus.observe_start("custom", || Err::<(), _>("no config".to_owned()));
+        let _ = bus.observe_stop("one", || Ok::<_, String>(()));
+
+        let flaky = metrics.service("flaky").unwrap();
+        assert_eq!(flaky.start_attempts, 3);
+        assert_eq!(flaky.restarts, 2);
+        assert_eq!(flaky.failures["FailedToStart"], 2);
+        assert_eq!(flaky.state, State::Running);
+        assert_eq!(flaky.start_latency.count(), 3);
+
+        let custom = metrics.service("custom").unwrap();
+        assert_eq!(custom.failures["Custom"], 1);
+
+        // Variants of layered services are kept through the bus as well
+        let breaker = CircuitBreaker::new(
+            FaultyService::new(Fault::FailFirst(5)),
+            1,
+            Duration::from_secs(60),
+        );
+        let mut sup = Supervisor::new(Strategy::OneForOne).with_events(bus);
+        sup.add(
+            "breaker",
+            Box::new(AsStartable(breaker)),
+            RestartPolicy::OnFailure { max_attempts: 2 },
+        );
+        assert!(sup.start().is_err());
+        let failures = metrics.service("breaker").unwrap().failures;
+        assert_eq!(failures["FailedToStart"], 1);
+        assert_eq!(failures["CircuitOpen"], 1);
+        assert_eq!(metrics.service("one").unwrap().state, State::Stopped);
+        assert!(metrics.service("missing").is_none());
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = Metrics::new()
+            .with_buckets(vec![1.0, 0.1])
+            .with_clock(Arc::new(crate::SimClock::new()));
+        let clock = metrics.clock.clone();
+        let _ = metrics.observe_start("db", || Err::<(), _>(Error::FailedToStart));
+        let _ = metrics.observe_start("db", || {
+            clock.sleep(Duration::from_millis(500));
+            Ok(())
+        });
+        metrics.set_state("a\"b", State::Stopped);
+
+        let text = metrics.render();
+        for line in &[
+            "# TYPE service_start_attempts_total counter",
+            "service_start_attempts_total{service=\"db\"} 2",
+            "service_start_failures_total{service=\"db\",error=\"FailedToStart\"} 1",
+            "service_restarts_total{service=\"db\"} 1",
+            "service_state{service=\"db\",state=\"running\"} 1",
+            "service_state{service=\"db\",state=\"failed\"} 0",
+            "service_state{service=\"a\\\"b\",state=\"stopped\"} 1",
+            "service_start_duration_seconds_bucket{service=\"db\",le=\"0.1\"} 1",
+            "service_start_duration_seconds_bucket{service=\"db\",le=\"1\"} 2",
+            "service_start_duration_seconds_bucket{service=\"db\",le=\"+Inf\"} 2",
+            "service_start_duration_seconds_sum{service=\"db\"} 0.5",
+            "service_start_duration_seconds_count{service=\"db\"} 2",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn http_endpoint() {
+        let metrics = Metrics::new();
+        let _ = metrics.observe_start("db", || Ok(()));
+        let server = metrics.serve(0).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let ok = get("/metrics");
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with(&metrics.render()));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        drop(server);
+    }
+}
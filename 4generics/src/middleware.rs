@@ -0,0 +1,635 @@
+use crate::clock::recv_before;
+use crate::supervisor::XorShift;
+use crate::{system_clock, AssocService, Backoff, Clock, Error, Health};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wraps a service in another service, like tower layers. Layers can be stacked with
+/// `ServiceBuilder`, so a service can be hardened without changing its implementation
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// Layer which leaves the service as is
+pub struct Identity;
+
+impl<S> Layer<S> for Identity {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> S {
+        inner
+    }
+}
+
+/// Two layers applied one after another, `outer` wraps the result of `inner`
+pub struct Stack<I, O> {
+    inner: I,
+    outer: O,
+}
+
+impl<S, I: Layer<S>, O: Layer<I::Service>> Layer<S> for Stack<I, O> {
+    type Service = O::Service;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// Builds a stack of layers, the first layer added is the outermost one
+pub struct ServiceBuilder<L> {
+    layer: L,
+}
+
+impl ServiceBuilder<Identity> {
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl Default for ServiceBuilder<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> ServiceBuilder<L> {
+    pub fn layer<T>(self, layer: T) -> ServiceBuilder<Stack<T, L>> {
+        ServiceBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    pub fn service<S>(&self, service: S) -> L::Service
+    where
+        L: Layer<S>,
+    {
+        self.layer.layer(service)
+    }
+}
+
+type Predicate = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// Starts the inner service again when it fails with an error the predicate marks as
+/// transient, waiting between attempts based on the backoff
+pub struct Retry<S> {
+    inner: S,
+    backoff: Backoff,
+    is_transient: Predicate,
+    rng: XorShift,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S> Retry<S> {
+    /// Retries every error, `backoff.max_attempts` is the total number of start attempts
+    pub fn new(inner: S, backoff: Backoff) -> Self {
+        Self {
+            inner,
+            backoff,
+            is_transient: Arc::new(|_| true),
+            rng: XorShift::from_time(),
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock the backoff delays are waited on
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Only retries errors for which `is_transient` returns true
+    pub fn retry_if<F>(mut self, is_transient: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.is_transient = Arc::new(is_transient);
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AssocService> AssocService for Retry<S>
+where
+    S::AssocError: Into<Error>,
+{
+    type AssocError = Error;
+
+    fn start(&mut self) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.inner.start() {
+                Ok(()) => return Ok(()),
+                Err(e) => e.into(),
+            };
+            if !(self.is_transient)(&err) {
+                return Err(err);
+            }
+            if attempt >= self.backoff.max_attempts {
+                return Err(Error::RetriesExhausted {
+                    attempts: attempt,
+                    last: Box::new(err),
+                });
+            }
+            self.clock
+                .sleep(self.backoff.jittered(attempt, &mut self.rng));
+            attempt += 1;
+        }
+    }
+    fn stop(&mut self) -> Result<(), Error> {
+        self.inner.stop().map_err(Into::into)
+    }
+    fn health(&self) -> Health {
+        self.inner.health()
+    }
+}
+
+/// Layer for `Retry`
+#[derive(Clone)]
+pub struct RetryLayer {
+    backoff: Backoff,
+    is_transient: Predicate,
+    clock: Arc<dyn Clock>,
+}
+
+impl RetryLayer {
+    pub fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            is_transient: Arc::new(|_| true),
+            clock: system_clock(),
+        }
+    }
+
+    pub fn retry_if<F>(mut self, is_transient: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.is_transient = Arc::new(is_transient);
+        self
+    }
+
+    /// Clock the backoff delays of every layered service are waited on
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = Retry<S>;
+
+    fn layer(&self, inner: S) -> Retry<S> {
+        Retry {
+            is_transient: self.is_transient.clone(),
+            ..Retry::new(inner, self.backoff.clone())
+        }
+        .with_clock(self.clock.clone())
+    }
+}
+
+/// State of a `CircuitBreaker`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    /// Starts go through to the service
+    Closed,
+    /// Starts fail straight away until the cooldown is over
+    Open,
+    /// Cooldown is over, the next start is let through as a probe
+    HalfOpen,
+}
+
+/// Stops starting the inner service after `threshold` failures in a row. Once `cooldown` has
+/// passed, one start is let through, which closes the circuit again if it succeeds
+pub struct CircuitBreaker<S> {
+    inner: S,
+    threshold: u32,
+    cooldown: Duration,
+    failures: u32,
+    opened_at: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S> CircuitBreaker<S> {
+    pub fn new(inner: S, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            inner,
+            threshold,
+            cooldown,
+            failures: 0,
+            opened_at: None,
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock the cooldown is measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(at) if self.clock.now() - at < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AssocService> AssocService for CircuitBreaker<S>
+where
+    S::AssocError: Into<Error>,
+{
+    type AssocError = Error;
+
+    fn start(&mut self) -> Result<(), Error> {
+        if self.state() == CircuitState::Open {
+            return Err(Error::CircuitOpen);
+        }
+        match self.inner.start() {
+            Ok(()) => {
+                self.failures = 0;
+                self.opened_at = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.failures += 1;
+                // A failed probe opens the circuit again straight away
+                if self.failures >= self.threshold || self.opened_at.is_some() {
+                    self.opened_at = Some(self.clock.now());
+                }
+                Err(e.into())
+            }
+        }
+    }
+    fn stop(&mut self) -> Result<(), Error> {
+        self.inner.stop().map_err(Into::into)
+    }
+    fn health(&self) -> Health {
+        match self.state() {
+            CircuitState::Closed => self.inner.health(),
+            _ => Health::Unhealthy("circuit open".to_owned()),
+        }
+    }
+}
+
+/// Layer for `CircuitBreaker`
+#[derive(Clone)]
+pub struct CircuitBreakerLayer {
+    threshold: u32,
+    cooldown: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock the cooldown of every layered service is measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreaker<S>;
+
+    fn layer(&self, inner: S) -> CircuitBreaker<S> {
+        CircuitBreaker::new(inner, self.threshold, self.cooldown).with_clock(self.clock.clone())
+    }
+}
+
+/// Fails a start which takes longer than the timeout. The start keeps running on its own
+/// thread, and the service can not be started again until it returns
+pub struct Timeout<S> {
+    inner: Arc<Mutex<S>>,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S> Timeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            timeout,
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock the timeout is measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<S> AssocService for Timeout<S>
+where
+    S: AssocService + Send + 'static,
+    S::AssocError: Into<Error>,
+{
+    type AssocError = Error;
+
+    fn start(&mut self) -> Result<(), Error> {
+        // Still locked by a start that timed out earlier. A start which panicked poisons the
+        // lock but no longer holds it, so the service can be started again
+        if let Err(TryLockError::WouldBlock) = self.inner.try_lock() {
+            return Err(Error::TimedOut(self.timeout));
+        }
+        let deadline = self.clock.now() + self.timeout;
+        let (tx, rx) = mpsc::channel();
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            // Unlocked before the result is sent, so a stop straight after does not find the
+            // service still busy
+            let res = inner.lock().unwrap_or_else(|e| e.into_inner()).start();
+            let _ = tx.send(res.map_err(Into::into));
+        });
+        match recv_before(&*self.clock, &rx, deadline) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(Error::TimedOut(self.timeout)),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::Custom("service panicked while starting".to_owned()))
+            }
+        }
+    }
+    fn stop(&mut self) -> Result<(), Error> {
+        match try_lock(&self.inner) {
+            Some(mut service) => service.stop().map_err(Into::into),
+            None => Err(Error::TimedOut(self.timeout)),
+        }
+    }
+    fn health(&self) -> Health {
+        match try_lock(&self.inner) {
+            Some(service) => service.health(),
+            None => Health::Unhealthy("service is still starting".to_owned()),
+        }
+    }
+}
+
+/// Locks the service unless a start which timed out still holds it. A lock poisoned by a
+/// start which panicked is taken anyway
+fn try_lock<S>(inner: &Mutex<S>) -> Option<MutexGuard<'_, S>> {
+    match inner.try_lock() {
+        Ok(service) => Some(service),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Layer for `Timeout`
+#[derive(Clone)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock the timeout of every layered service is measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Timeout<S> {
+        Timeout::new(inner, self.timeout).with_clock(self.clock.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Fault, FaultyService, ServiceOne, ServiceThree, ServiceTwo, SimClock};
+
+    fn attempts(max_attempts: u32) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(0),
+            max: Duration::from_millis(0),
+            multiplier: 2,
+            jitter: 0.0,
+            max_attempts,
+        }
+    }
+
+    /// Fails the first `fail_times` starts
+    struct Flaky {
+        fail_times: u32,
+        starts: u32,
+    }
+    impl AssocService for Flaky {
+        type AssocError = Error;
+        fn start(&mut self) -> Result<(), Error> {
+            self.starts += 1;
+            if self.starts <= self.fail_times {
+                Err(Error::FailedToStart)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn retry() {
+        let mut s = Retry::new(
+            Flaky {
+                fail_times: 2,
+                starts: 0,
+            },
+            attempts(3),
+        );
+        assert_eq!(s.start(), Ok(()));
+        assert_eq!(s.get_ref().starts, 3);
+
+        let mut s = Retry::new(ServiceThree { fails: true }, attempts(2));
+        assert_eq!(
+            s.start(),
+            Err(Error::RetriesExhausted {
+                attempts: 2,
+                last: Box::new(Error::FailedToStart)
+            })
+        );
+
+        // Only FailedToStart is transient, so the custom error is returned straight away
+        let mut s = Retry::new(ServiceTwo, attempts(5)).retry_if(|e| *e == Error::FailedToStart);
+        assert_eq!(
+            s.start(),
+            Err(Error::Custom("Service two failed!".to_owned()))
+        );
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let clock = Arc::new(SimClock::manual());
+        let mut s = CircuitBreaker::new(
+            Flaky {
+                fail_times: 3,
+                starts: 0,
+            },
+            2,
+            Duration::from_secs(20),
+        )
+        .with_clock(clock.clone());
+        assert_eq!(s.start(), Err(Error::FailedToStart));
+        assert_eq!(s.state(), CircuitState::Closed);
+        assert_eq!(s.start(), Err(Error::FailedToStart));
+        assert_eq!(s.state(), CircuitState::Open);
+        assert_eq!(s.start(), Err(Error::CircuitOpen));
+        assert_eq!(s.get_ref().starts, 2);
+
+        clock.advance(Duration::from_secs(19));
+        assert_eq!(s.state(), CircuitState::Open);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(s.state(), CircuitState::HalfOpen);
+        // Failed probe opens the circuit again
+        assert_eq!(s.start(), Err(Error::FailedToStart));
+        assert_eq!(s.state(), CircuitState::Open);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(s.start(), Ok(()));
+        assert_eq!(s.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn timeout() {
+        let clock = Arc::new(SimClock::manual());
+        let hangs =
+            FaultyService::new(Fault::Hang(Duration::from_secs(10))).with_clock(clock.clone());
+        let mut s = TimeoutLayer::new(Duration::from_millis(20))
+            .with_clock(clock.clone())
+            .layer(hangs);
+        let advance = {
+            let clock = clock.clone();
+            thread::spawn(move || {
+                clock.wait_for_sleepers(2);
+                clock.advance(Duration::from_millis(20));
+            })
+        };
+        assert_eq!(s.start(), Err(Error::TimedOut(Duration::from_millis(20))));
+        advance.join().unwrap();
+        // Service is still busy with the first start
+        assert!(s.health() != Health::Healthy);
+        clock.advance(Duration::from_secs(10));
+
+        let mut s = Timeout::new(ServiceOne, Duration::from_secs(1));
+        assert_eq!(s.start(), Ok(()));
+
+        // A start which panicked does not leave the service looking busy
+        let mut s = Timeout::new(
+            Flaky {
+                fail_times: 0,
+                starts: 0,
+            },
+            Duration::from_secs(1),
+        );
+        let inner = s.inner.clone();
+        let _ = thread::spawn(move || {
+            let _service = inner.lock().unwrap();
+            panic!("start panicked");
+        })
+        .join();
+        assert!(s.inner.is_poisoned());
+        assert_eq!(s.health(), Health::Healthy);
+        assert_eq!(s.start(), Ok(()));
+        assert_eq!(s.stop(), Ok(()));
+    }
+
+    #[test]
+    fn timeout_on_simulated_clock() {
+        let clock = Arc::new(SimClock::manual());
+        let hangs =
+            FaultyService::new(Fault::Hang(Duration::from_secs(60))).with_clock(clock.clone());
+        let mut s = Timeout::new(hangs, Duration::from_secs(5)).with_clock(clock.clone());
+        let start = {
+            let clock = clock.clone();
+            thread::spawn(move || {
+                let res = s.start();
+                (res, clock.elapsed())
+            })
+        };
+        // Both the hanging service and the timeout are waiting on the clock
+        clock.wait_for_sleepers(2);
+        clock.advance(Duration::from_secs(5));
+        let (res, at) = start.join().unwrap();
+        assert_eq!(res, Err(Error::TimedOut(Duration::from_secs(5))));
+        assert_eq!(at, Duration::from_secs(5));
+        clock.advance(Duration::from_secs(60));
+    }
+
+    #[test]
+    fn stacked_layers() {
+        let mut s = ServiceBuilder::new()
+            .layer(RetryLayer::new(attempts(3)).retry_if(|e| *e != Error::CircuitOpen))
+            .layer(CircuitBreakerLayer::new(2, Duration::from_secs(60)))
+            .layer(TimeoutLayer::new(Duration::from_secs(1)))
+            .service(ServiceThree { fails: true });
+        // Circuit opens after two failures, which stops the retries early
+        assert_eq!(s.start(), Err(Error::CircuitOpen));
+        assert_eq!(s.get_ref().state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn layers_use_their_clock() {
+        let clock = Arc::new(SimClock::new());
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2,
+            jitter: 0.0,
+            max_attempts: 3,
+        };
+        let mut s = ServiceBuilder::new()
+            .layer(RetryLayer::new(backoff).with_clock(clock.clone()))
+            .layer(CircuitBreakerLayer::new(5, Duration::from_secs(30)).with_clock(clock.clone()))
+            .service(ServiceThree { fails: true });
+        assert_eq!(
+            s.start(),
+            Err(Error::RetriesExhausted {
+                attempts: 3,
+                last: Box::new(Error::FailedToStart)
+            })
+        );
+        assert_eq!(
+            clock.sleeps(),
+            vec![Duration::from_secs(1), Duration::from_secs(2)]
+        );
+
+        let mut s = CircuitBreakerLayer::new(1, Duration::from_secs(30))
+            .with_clock(clock.clone())
+            .layer(ServiceThree { fails: true });
+        assert_eq!(s.start(), Err(Error::FailedToStart));
+        assert_eq!(s.state(), CircuitState::Open);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(s.state(), CircuitState::HalfOpen);
+    }
+}
@@ -0,0 +1,330 @@
+use crate::clock::recv_before;
+use crate::{system_clock, Clock, StartableService};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a single service did when started with `start_all_parallel`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StartOutcome {
+    Ok,
+    Err(String),
+    /// Start did not return before the deadline, it may still be running in the background
+    TimedOut,
+    /// Start panicked, holds the panic message
+    Panicked(String),
+    /// Start never began, since every worker was still held by a start which timed out
+    NotStarted,
+}
+
+/// Limits used by `start_all_parallel`
+#[derive(Debug, Clone)]
+pub struct ParallelOptions {
+    /// Maximum number of services being started at the same time
+    pub concurrency: usize,
+    /// How long each service has to start, counted from when its own start begins
+    pub timeout: Duration,
+    /// Clock the timeout is measured with
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            timeout: Duration::from_secs(30),
+            clock: system_clock(),
+        }
+    }
+}
+
+/// Service handed back from `start_all_parallel` along with how it started
+pub struct Completed {
+    pub outcome: StartOutcome,
+    /// `None` if the service timed out or panicked, since it can no longer be used safely
+    pub service: Option<Box<dyn StartableService + Send>>,
+}
+
+type Finished = (
+    usize,
+    StartOutcome,
+    Option<Box<dyn StartableService + Send>>,
+);
+
+/// Sent from a worker to `start_all_parallel`
+enum Message {
+    /// Worker began starting the service at this index
+    Started(usize),
+    Finished(Finished),
+}
+
+/// Starts services on `concurrency` worker threads. Unlike `start_all`, a service that is slow,
+/// hangs or panics does not hold up or take down the others. A service which times out keeps
+/// its worker until its start returns, so no more than `concurrency` starts ever run at once,
+/// and services still queued wait for a free worker. If every worker is still held by a start
+/// which timed out after another `timeout`, the services still queued are handed back
+/// `NotStarted` rather than waiting on them. Results are returned in the same order as
+/// `services`
+pub fn start_all_parallel(
+    services: Vec<Box<dyn StartableService + Send>>,
+    opts: &ParallelOptions,
+) -> Vec<Completed> {
+    let total = services.len();
+    let mut results: Vec<Option<Completed>> = (0..total).map(|_| None).collect();
+    let queue = Arc::new(Mutex::new(services.into_iter().enumerate()));
+    let (tx, rx) = mpsc::channel::<Message>();
+    for _ in 0..opts.concurrency.max(1).min(total) {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+            let (i, mut service) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let _ = tx.send(Message::Started(i));
+            let res = panic::catch_unwind(AssertUnwindSafe(|| service.start()));
+            let finished = match res {
+                Ok(Ok(())) => (i, StartOutcome::Ok, Some(service)),
+                Ok(Err(e)) => (i, StartOutcome::Err(e), Some(service)),
+                Err(payload) => (i, StartOutcome::Panicked(panic_message(&*payload)), None),
+            };
+            // Receiver is gone if every service already finished or timed out
+            let _ = tx.send(Message::Finished(finished));
+        });
+    }
+    drop(tx);
+
+    // Deadline of each service currently starting
+    let mut running: HashMap<usize, Instant> = HashMap::new();
+    // Deadline for a worker to come free, while none is busy with a start still in time
+    let mut stalled: Option<Instant> = None;
+    let mut left = total;
+    while left > 0 {
+        let deadline = match running.values().min() {
+            Some(&deadline) => deadline,
+            None => *stalled.get_or_insert_with(|| opts.clock.now() + opts.timeout),
+        };
+        match recv_before(&*opts.clock, &rx, deadline) {
+            Ok(Message::Started(i)) => {
+                stalled = None;
+                running.insert(i, opts.clock.now() + opts.timeout);
+            }
+            Ok(Message::Finished((i, outcome, service))) => {
+                // Services which already timed out are no longer tracked
+                if running.remove(&i).is_some() {
+                    results[i] = Some(Completed { outcome, service });
+                    left -= 1;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = opts.clock.now();
+                running.retain(|&i, deadline| {
+                    if *deadline > now {
+                        return true;
+                    }
+                    results[i] = Some(Completed {
+                        outcome: StartOutcome::TimedOut,
+                        service: None,
+                    });
+                    left -= 1;
+                    false
+                });
+                if running.is_empty() && stalled.is_some_and(|d| d <= now) {
+                    // * A service taken by a worker just before this is still reported as
+                    // * started, so only the ones left in the queue are given up on
+                    let queued: Vec<_> = queue
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .by_ref()
+                        .collect();
+                    for (i, service) in queued {
+                        results[i] = Some(Completed {
+                            outcome: StartOutcome::NotStarted,
+                            service: Some(service),
+                        });
+                        left -= 1;
+                    }
+                    stalled = None;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                unreachable!("workers only exit once every service has been taken and reported")
+            }
+        }
+    }
+
+    results
+        .into_iter()
+        .map(|r| r.expect("every service has finished or timed out"))
+        .collect()
+}
+
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ServiceOne, ServiceTwo};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Slow(Duration);
+    impl StartableService for Slow {
+        fn start(&mut self) -> Result<(), String> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+    }
+
+    struct Panics;
+    impl StartableService for Panics {
+        fn start(&mut self) -> Result<(), String> {
+            panic!("service exploded")
+        }
+    }
+
+    /// Tracks the most services that were starting at the same time
+    struct Counted {
+        current: Arc<AtomicUsize>,
+        max: Arc<AtomicUsize>,
+    }
+    impl StartableService for Counted {
+        fn start(&mut self) -> Result<(), String> {
+            let now = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn outcomes_per_service() {
+        let services: Vec<Box<dyn StartableService + Send>> = vec![
+            Box::new(ServiceOne),
+            Box::new(ServiceTwo),
+            Box::new(Slow(Duration::from_secs(10))),
+            Box::new(Panics),
+        ];
+        let opts = ParallelOptions {
+            concurrency: 4,
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let results = start_all_parallel(services, &opts);
+        let outcomes: Vec<_> = results.iter().map(|c| c.outcome.clone()).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                StartOutcome::Ok,
+                StartOutcome::Err("Service two failed!".to_owned()),
+                StartOutcome::TimedOut,
+                StartOutcome::Panicked("service exploded".to_owned()),
+            ]
+        );
+        assert!(results[0].service.is_some());
+        assert!(results[2].service.is_none());
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        let current = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+        let services = (0..6)
+            .map(|_| {
+                Box::new(Counted {
+                    current: current.clone(),
+                    max: max.clone(),
+                }) as Box<dyn StartableService + Send>
+            })
+            .collect();
+        let opts = ParallelOptions {
+            concurrency: 2,
+            ..Default::default()
+        };
+        let results = start_all_parallel(services, &opts);
+        assert!(results.iter().all(|c| c.outcome == StartOutcome::Ok));
+        assert!(max.load(Ordering::SeqCst) <= 2);
+    }
+
+    /// Sets `done` once its start returns
+    struct SlowDone {
+        delay: Duration,
+        done: Arc<AtomicBool>,
+    }
+    impl StartableService for SlowDone {
+        fn start(&mut self) -> Result<(), String> {
+            thread::sleep(self.delay);
+            self.done.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Fails unless `done` was set before it started
+    struct AfterDone(Arc<AtomicBool>);
+    impl StartableService for AfterDone {
+        fn start(&mut self) -> Result<(), String> {
+            if self.0.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err("started next to a timed out service".to_owned())
+            }
+        }
+    }
+
+    #[test]
+    fn queue_gives_up_on_hung_workers() {
+        let services: Vec<Box<dyn StartableService + Send>> = vec![
+            Box::new(Slow(Duration::from_secs(3600))),
+            Box::new(ServiceOne),
+        ];
+        let opts = ParallelOptions {
+            concurrency: 1,
+            timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let results = start_all_parallel(services, &opts);
+        let outcomes: Vec<_> = results.iter().map(|c| c.outcome.clone()).collect();
+        assert_eq!(
+            outcomes,
+            vec![StartOutcome::TimedOut, StartOutcome::NotStarted]
+        );
+        // Never started, so it is handed back to be started some other way
+        assert!(results[1].service.is_some());
+    }
+
+    /// Worker comes free before the queue gives up on it, but only after the timeout
+    #[test]
+    fn timed_out_service_keeps_its_slot() {
+        let done = Arc::new(AtomicBool::new(false));
+        let services: Vec<Box<dyn StartableService + Send>> = vec![
+            Box::new(SlowDone {
+                delay: Duration::from_millis(100),
+                done: done.clone(),
+            }),
+            Box::new(AfterDone(done)),
+        ];
+        let opts = ParallelOptions {
+            concurrency: 1,
+            timeout: Duration::from_millis(80),
+            ..Default::default()
+        };
+        let outcomes: Vec<_> = start_all_parallel(services, &opts)
+            .into_iter()
+            .map(|c| c.outcome)
+            .collect();
+        assert_eq!(outcomes, vec![StartOutcome::TimedOut, StartOutcome::Ok]);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{system_clock, Clock, Health};
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type CheckFn = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// What a `Probe` checks
+#[derive(Clone)]
+pub enum Check {
+    /// A TCP connection can be opened to the address
+    Tcp(SocketAddr),
+    /// The file exists and, if `max_age` is set, was modified within it
+    File {
+        path: PathBuf,
+        max_age: Option<Duration>,
+    },
+    /// The command exits with code 0
+    Command { program: String, args: Vec<String> },
+    /// The closure returns `Ok`, it is not interrupted if it takes longer than the timeout
+    Custom(CheckFn),
+}
+
+impl Check {
+    /// Checks a TCP port on localhost
+    pub fn tcp_port(port: u16) -> Self {
+        Check::Tcp(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    pub fn custom<F: Fn() -> Result<(), String> + Send + Sync + 'static>(f: F) -> Self {
+        Check::Custom(Arc::new(f))
+    }
+
+    fn run(&self, timeout: Duration, clock: &dyn Clock) -> Result<(), String> {
+        match self {
+            Check::Tcp(addr) => TcpStream::connect_timeout(addr, timeout)
+                .map(drop)
+                .map_err(|e| format!("connect to {} failed: {}", addr, e)),
+            Check::File { path, max_age } => {
+                let meta = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let max_age = match max_age {
+                    Some(age) => *age,
+                    None => return Ok(()),
+                };
+                let modified = meta.modified().map_err(|e| e.to_string())?;
+                let age = clock
+                    .system_time()
+                    .duration_since(modified)
+                    .unwrap_or_default();
+                if age <= max_age {
+                    Ok(())
+                } else {
+                    Err(format!("{} not modified for {:?}", path.display(), age))
+                }
+            }
+            Check::Command { program, args } => run_command(program, args, timeout, clock),
+            Check::Custom(f) => f(),
+        }
+    }
+}
+
+impl fmt::Debug for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Tcp(addr) => write!(f, "Tcp({})", addr),
+            Check::File { path, max_age } => write!(f, "File({:?}, {:?})", path, max_age),
+            Check::Command { program, args } => write!(f, "Command({} {:?})", program, args),
+            Check::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Runs a `Check` and turns its results into a `Health`. Health only changes after
+/// `failure_threshold` failures or `success_threshold` successes in a row, so a single
+/// failed check does not mark a service as unhealthy. Probes start out healthy.
+///
+/// Every probe is a liveness probe: a supervised service whose probe turns unhealthy is
+/// restarted if its policy allows. There is no readiness probe, whether a service is ready is
+/// up to its `start`
+#[derive(Debug, Clone)]
+pub struct Probe {
+    check: Check,
+    interval: Duration,
+    timeout: Duration,
+    success_threshold: u32,
+    failure_threshold: u32,
+    successes: u32,
+    failures: u32,
+    health: Health,
+    last_run: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Probe {
+    /// Probe checked every 10s with a 1s timeout, which is unhealthy after 3 failures
+    pub fn new(check: Check) -> Self {
+        Self {
+            check,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+            success_threshold: 1,
+            failure_threshold: 3,
+            successes: 0,
+            failures: 0,
+            health: Health::Healthy,
+            last_run: None,
+            clock: system_clock(),
+        }
+    }
+
+    /// Clock the interval, timeout and file ages are measured with
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn success_threshold(mut self, n: u32) -> Self {
+        self.success_threshold = n.max(1);
+        self
+    }
+
+    pub fn failure_threshold(mut self, n: u32) -> Self {
+        self.failure_threshold = n.max(1);
+        self
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    /// Runs the check if at least `interval` has passed since the last run
+    pub fn poll(&mut self) -> &Health {
+        let now = self.clock.now();
+        let waiting = matches!(self.last_run, Some(last) if now - last < self.interval);
+        if !waiting {
+            self.run();
+        }
+        &self.health
+    }
+
+    /// Runs the check straight away
+    pub fn run(&mut self) -> &Health {
+        self.last_run = Some(self.clock.now());
+        match self.check.run(self.timeout, &*self.clock) {
+            Ok(()) => {
+                self.failures = 0;
+                self.successes += 1;
+                if self.successes >= self.success_threshold {
+                    self.health = Health::Healthy;
+                }
+            }
+            Err(e) => {
+                self.successes = 0;
+                self.failures += 1;
+                if self.failures >= self.failure_threshold {
+                    self.health = Health::Unhealthy(e);
+                }
+            }
+        }
+        &self.health
+    }
+
+    /// Forgets previous results, used when the service is restarted
+    pub fn reset(&mut self) {
+        self.successes = 0;
+        self.failures = 0;
+        self.health = Health::Healthy;
+        self.last_run = None;
+    }
+}
+
+fn run_command(
+    program: &str,
+    args: &[String],
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<(), String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("failed to run {}: {}", program, e))?;
+    let deadline = clock.now() + timeout;
+    loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => return Err(format!("{} exited with {}", program, status)),
+            None if clock.now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} did not finish within {:?}", program, timeout));
+            }
+            None => clock.sleep(Duration::from_millis(5)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn tcp_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut probe = Probe::new(Check::tcp_port(port))
+            .failure_threshold(2)
+            .timeout(Duration::from_millis(200));
+        assert_eq!(probe.run(), &Health::Healthy);
+
+        drop(listener);
+        // First failure is below the threshold
+        assert_eq!(probe.run(), &Health::Healthy);
+        assert!(matches!(probe.run(), Health::Unhealthy(_)));
+
+        let _listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        assert_eq!(probe.run(), &Health::Healthy);
+    }
+
+    #[test]
+    fn file_probe() {
+        let path = std::env::temp_dir().join(format!("generics-probe-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let check = Check::File {
+            path: path.clone(),
+            max_age: Some(Duration::from_secs(60)),
+        };
+        let mut probe = Probe::new(check).failure_threshold(1);
+        assert!(matches!(probe.run(), Health::Unhealthy(_)));
+        fs::write(&path, b"").unwrap();
+        assert_eq!(probe.run(), &Health::Healthy);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_probe() {
+        let ok = Check::Command {
+            program: "true".to_owned(),
+            args: vec![],
+        };
+        assert_eq!(Probe::new(ok).run(), &Health::Healthy);
+        let fails = Check::Command {
+            program: "sh".to_owned(),
+            args: vec!["-c".to_owned(), "exit 1".to_owned()],
+        };
+        assert!(matches!(
+            Probe::new(fails).failure_threshold(1).run(),
+            Health::Unhealthy(_)
+        ));
+    }
+
+    #[test]
+    fn custom_probe() {
+        let mut probe = Probe::new(Check::custom(|| Err("nope".to_owned())))
+            .failure_threshold(1)
+            .success_threshold(2)
+            .interval(Duration::from_secs(60));
+        assert_eq!(probe.poll(), &Health::Unhealthy("nope".to_owned()));
+        probe.check = Check::custom(|| Ok(()));
+        // Not due again yet, so the check is not run
+        assert_eq!(probe.poll(), &Health::Unhealthy("nope".to_owned()));
+        assert_eq!(probe.run(), &Health::Unhealthy("nope".to_owned()));
+        assert_eq!(probe.run(), &Health::Healthy);
+    }
+}
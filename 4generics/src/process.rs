@@ -0,0 +1,380 @@
+use crate::clock::recv_before;
+use crate::{system_clock, AssocService, Clock, Error, Health, StartableService};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Number of stderr lines kept to report when the process fails
+const STDERR_TAIL: usize = 10;
+/// How long an exited process's stderr is read for. The pipe only closes once every process
+/// holding it is gone, which a process left behind by the child can put off indefinitely
+const STDERR_WAIT: Duration = Duration::from_millis(200);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// When a started process counts as ready
+#[derive(Debug, Clone, PartialEq)]
+pub enum Readiness {
+    /// A line containing this text is printed to stdout, matched literally
+    StdoutContains(String),
+    /// The file exists
+    FileExists(PathBuf),
+    /// The process is still running after this long
+    AliveFor(Duration),
+}
+
+struct Running {
+    child: Child,
+    stderr: Arc<Mutex<VecDeque<String>>>,
+    /// Disconnected once the stderr reader has read everything
+    stderr_done: Receiver<()>,
+}
+
+impl Running {
+    /// Error for a process which exited on its own, including the end of its stderr
+    fn exited(&mut self, status: ExitStatus, clock: &dyn Clock) -> Error {
+        // Reader usually finishes as soon as the process is gone, so every stderr line is
+        // included, but it is not waited on for long
+        let _ = recv_before(clock, &self.stderr_done, clock.now() + STDERR_WAIT);
+        let tail = self.stderr.lock().unwrap_or_else(|e| e.into_inner());
+        Error::ProcessExited {
+            code: status.code(),
+            stderr_tail: tail.iter().cloned().collect::<Vec<_>>().join("\n"),
+        }
+    }
+}
+
+/// Service backed by a local executable, which is started as a child process
+pub struct ProcessService {
+    program: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    readiness: Readiness,
+    ready_timeout: Duration,
+    grace_period: Duration,
+    running: Mutex<Option<Running>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ProcessService {
+    /// Process which is ready as soon as it has been spawned and survived 100ms
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_owned(),
+            args: Vec::new(),
+            env: Vec::new(),
+            readiness: Readiness::AliveFor(Duration::from_millis(100)),
+            ready_timeout: Duration::from_secs(30),
+            grace_period: Duration::from_secs(10),
+            running: Mutex::new(None),
+            clock: system_clock(),
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_owned());
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn ready_when(mut self, readiness: Readiness) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    /// How long the process has to become ready before it is killed (default 30s)
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = timeout;
+        self
+    }
+
+    /// How long the process has to exit after SIGTERM before it is killed (default 10s)
+    pub fn grace_period(mut self, grace: Duration) -> Self {
+        self.grace_period = grace;
+        self
+    }
+
+    /// Clock readiness, timeouts and the grace period are measured with
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Process id, if the process has been started
+    pub fn pid(&self) -> Option<u32> {
+        self.lock().as_ref().map(|r| r.child.id())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Running>> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn spawn(&self) -> Result<(Running, Receiver<String>), Error> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Custom(format!("failed to spawn {}: {}", self.program, e)))?;
+
+        // Pipes are always drained, so the process never blocks on a full pipe
+        let (tx, lines) = mpsc::channel();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    // Readiness no longer cares about stdout once it is ready
+                    Ok(line) => drop(tx.send(line)),
+                    Err(_) => break,
+                }
+            }
+        });
+        let stderr = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_TAIL)));
+        let stderr_done = spawn_tail_reader(
+            child.stderr.take().expect("stderr is piped"),
+            stderr.clone(),
+        );
+        Ok((
+            Running {
+                child,
+                stderr,
+                stderr_done,
+            },
+            lines,
+        ))
+    }
+
+    fn wait_ready(&self, running: &mut Running, lines: &Receiver<String>) -> Result<(), Error> {
+        let started = self.clock.now();
+        loop {
+            if let Some(status) = running.child.try_wait().map_err(io_error)? {
+                return Err(running.exited(status, &*self.clock));
+            }
+            let ready = match &self.readiness {
+                Readiness::StdoutContains(text) => lines.try_iter().any(|l| l.contains(text)),
+                Readiness::FileExists(path) => path.exists(),
+                Readiness::AliveFor(d) => self.clock.now() - started >= *d,
+            };
+            if ready {
+                return Ok(());
+            }
+            if self.clock.now() - started >= self.ready_timeout {
+                let _ = running.child.kill();
+                let _ = running.child.wait();
+                return Err(Error::TimedOut(self.ready_timeout));
+            }
+            self.clock.sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl AssocService for ProcessService {
+    type AssocError = Error;
+
+    /// Spawns the process and waits for it to be ready
+    fn start(&mut self) -> Result<(), Error> {
+        let mut guard = self.lock();
+        if let Some(running) = guard.as_mut() {
+            if running.child.try_wait().map_err(io_error)?.is_none() {
+                return Err(Error::Custom(format!(
+                    "{} is already running",
+                    self.program
+                )));
+            }
+        }
+        let (mut running, lines) = self.spawn()?;
+        self.wait_ready(&mut running, &lines)?;
+        *guard = Some(running);
+        Ok(())
+    }
+
+    /// Sends SIGTERM, then SIGKILL if the process is still running after the grace period.
+    /// Errors if the process had already exited with a non zero code
+    fn stop(&mut self) -> Result<(), Error> {
+        let mut running = match self.lock().take() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        if let Some(status) = running.child.try_wait().map_err(io_error)? {
+            return match status.code() {
+                Some(0) => Ok(()),
+                _ => Err(running.exited(status, &*self.clock)),
+            };
+        }
+
+        // SAFETY: signalling a child process which has not been reaped yet, so its pid is
+        // still reserved for it
+        unsafe {
+            libc::kill(running.child.id() as libc::pid_t, libc::SIGTERM);
+        }
+        let deadline = self.clock.now() + self.grace_period;
+        while self.clock.now() < deadline {
+            if let Some(status) = running.child.try_wait().map_err(io_error)? {
+                // Killed by the signal (no code) is the expected way to exit
+                return match status.code() {
+                    None | Some(0) => Ok(()),
+                    Some(_) => Err(running.exited(status, &*self.clock)),
+                };
+            }
+            self.clock.sleep(POLL_INTERVAL);
+        }
+        running.child.kill().map_err(io_error)?;
+        running.child.wait().map_err(io_error)?;
+        Ok(())
+    }
+
+    fn health(&self) -> Health {
+        match self.lock().as_mut().map(|r| r.child.try_wait()) {
+            Some(Ok(None)) => Health::Healthy,
+            Some(Ok(Some(status))) => Health::Unhealthy(format!("process exited with {}", status)),
+            Some(Err(e)) => Health::Unhealthy(e.to_string()),
+            None => Health::Unhealthy("process is not running".to_owned()),
+        }
+    }
+}
+
+impl StartableService for ProcessService {
+    fn start(&mut self) -> Result<(), String> {
+        AssocService::start(self).map_err(|e| e.to_string())
+    }
+    fn stop(&mut self) -> Result<(), String> {
+        AssocService::stop(self).map_err(|e| e.to_string())
+    }
+    fn health(&self) -> Health {
+        AssocService::health(self)
+    }
+    fn start_typed(&mut self) -> Result<(), Error> {
+        AssocService::start(self)
+    }
+    fn stop_typed(&mut self) -> Result<(), Error> {
+        AssocService::stop(self)
+    }
+}
+
+impl Drop for ProcessService {
+    fn drop(&mut self) {
+        if let Some(mut running) = self.lock().take() {
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+/// Keeps the last `STDERR_TAIL` lines of `pipe` in `tail`. The returned receiver disconnects
+/// once the pipe has been read to the end
+fn spawn_tail_reader<R: Read + Send + 'static>(
+    pipe: R,
+    tail: Arc<Mutex<VecDeque<String>>>,
+) -> Receiver<()> {
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        // Dropped along with the thread
+        let _done = done;
+        for line in BufReader::new(pipe).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            let mut tail = tail.lock().unwrap_or_else(|e| e.into_inner());
+            if tail.len() == STDERR_TAIL {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+    });
+    finished
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::Custom(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::time::Instant;
+
+    fn sh(script: &str) -> ProcessService {
+        ProcessService::new("sh").arg("-c").arg(script)
+    }
+
+    #[test]
+    fn ready_on_stdout_line() {
+        let mut s = sh("echo starting; echo listening on 8080; exec sleep 10")
+            .ready_when(Readiness::StdoutContains("listening".to_owned()));
+        assert_eq!(AssocService::start(&mut s), Ok(()));
+        assert_eq!(AssocService::health(&s), Health::Healthy);
+        assert!(AssocService::start(&mut s).is_err());
+        assert_eq!(AssocService::stop(&mut s), Ok(()));
+        assert!(AssocService::health(&s) != Health::Healthy);
+    }
+
+    #[test]
+    fn ready_when_file_exists() {
+        let path = std::env::temp_dir().join(format!("generics-ready-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut s = sh("sleep 0.05; touch \"$READY_FILE\"; exec sleep 10")
+            .env("READY_FILE", path.to_str().unwrap())
+            .ready_when(Readiness::FileExists(path.clone()));
+        assert_eq!(AssocService::start(&mut s), Ok(()));
+        assert!(path.exists());
+        assert_eq!(AssocService::stop(&mut s), Ok(()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn exit_before_ready() {
+        let mut s = sh("echo first >&2; echo boom >&2; exit 3")
+            .ready_when(Readiness::AliveFor(Duration::from_secs(5)));
+        assert_eq!(
+            AssocService::start(&mut s),
+            Err(Error::ProcessExited {
+                code: Some(3),
+                stderr_tail: "first\nboom".to_owned()
+            })
+        );
+
+        // A process left behind keeps stderr open, which does not hold up the error
+        let mut s = sh("echo boom >&2; sleep 10 & exit 3")
+            .ready_when(Readiness::AliveFor(Duration::from_secs(5)));
+        let started = Instant::now();
+        assert_eq!(
+            AssocService::start(&mut s),
+            Err(Error::ProcessExited {
+                code: Some(3),
+                stderr_tail: "boom".to_owned()
+            })
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut s = sh("exec sleep 10")
+            .ready_when(Readiness::StdoutContains("never".to_owned()))
+            .ready_timeout(Duration::from_millis(50));
+        assert_eq!(
+            AssocService::start(&mut s),
+            Err(Error::TimedOut(Duration::from_millis(50)))
+        );
+    }
+
+    #[test]
+    fn kill_after_grace_period() {
+        let mut s = sh("trap '' TERM; echo ready; while true; do sleep 0.01; done")
+            .ready_when(Readiness::StdoutContains("ready".to_owned()))
+            .grace_period(Duration::from_millis(50));
+        assert_eq!(StartableService::start(&mut s), Ok(()));
+        let started = Instant::now();
+        assert_eq!(StartableService::stop(&mut s), Ok(()));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
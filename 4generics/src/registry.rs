@@ -0,0 +1,344 @@
+use crate::error::message;
+use crate::{system_clock, Clock, Error, EventBus, Managed, StartableService, State};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Service which can be downcast back to its concrete type. Implemented for every sendable
+/// `StartableService`, so it never needs to be implemented by hand
+pub trait DynService: StartableService + Send {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: StartableService + Send + Any> DynService for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Snapshot of a registered service, as reported by `ServiceRegistry::status`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: State,
+    /// Time since the service last started, `None` unless it is running
+    pub uptime: Option<Duration>,
+    /// Number of start attempts after the first one
+    pub restarts: u32,
+    /// Most recent start or stop error, kept after the service recovers
+    pub last_error: Option<String>,
+}
+
+struct Entry {
+    name: String,
+    service: Managed<dyn DynService>,
+    started_at: Option<Instant>,
+    starts: u32,
+    last_error: Option<String>,
+}
+
+/// Services keyed by name, which can be added, removed, started and stopped at runtime
+pub struct ServiceRegistry {
+    /// Kept in registration order, which is the order `start_all` uses
+    services: Vec<Entry>,
+    /// Names of running services, in the order they were started
+    started: Vec<String>,
+    events: EventBus,
+    clock: Arc<dyn Clock>,
+}
+
+impl ServiceRegistry {
+    pub fn new() -> Self {
+        Self {
+            services: Vec::new(),
+            started: Vec::new(),
+            events: EventBus::new(),
+            clock: system_clock(),
+        }
+    }
+
+    /// Bus to report every start and stop of the registered services to
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Clock uptimes are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn register<S: StartableService + Send + 'static>(
+        &mut self,
+        name: &str,
+        service: S,
+    ) -> Result<(), Error> {
+        self.register_boxed(name, Box::new(service))
+    }
+
+    pub fn register_boxed(
+        &mut self,
+        name: &str,
+        service: Box<dyn DynService>,
+    ) -> Result<(), Error> {
+        if self.find(name).is_some() {
+            return Err(Error::DuplicateService(name.to_owned()));
+        }
+        self.services.push(Entry {
+            name: name.to_owned(),
+            service: Managed::new(service),
+            started_at: None,
+            starts: 0,
+            last_error: None,
+        });
+        Ok(())
+    }
+
+    /// Removes a service, stopping it first if it is running
+    pub fn deregister(&mut self, name: &str) -> Result<Box<dyn DynService>, Error> {
+        let i = self.index(name)?;
+        if self.services[i].service.state() == &State::Running {
+            self.stop(name)?;
+        }
+        Ok(self.services.remove(i).service.into_inner())
+    }
+
+    /// Swaps in a new service under an existing name, stopping the old one first if it is
+    /// running. The new service starts out not running, keeping the name's restart count
+    pub fn replace(
+        &mut self,
+        name: &str,
+        service: Box<dyn DynService>,
+    ) -> Result<Box<dyn DynService>, Error> {
+        let i = self.index(name)?;
+        if self.services[i].service.state() == &State::Running {
+            self.stop(name)?;
+        }
+        let entry = &mut self.services[i];
+        entry.started_at = None;
+        let old = std::mem::replace(&mut entry.service, Managed::new(service));
+        Ok(old.into_inner())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    /// Registered names, in registration order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.services.iter().map(|e| e.name.as_str())
+    }
+
+    pub fn state(&self, name: &str) -> Option<&State> {
+        self.find(name).map(|i| self.services[i].service.state())
+    }
+
+    pub fn status(&self, name: &str) -> Option<ServiceStatus> {
+        let entry = &self.services[self.find(name)?];
+        let state = entry.service.state().clone();
+        let uptime = match state {
+            State::Running => entry.started_at.map(|at| self.clock.now() - at),
+            _ => None,
+        };
+        Some(ServiceStatus {
+            name: entry.name.clone(),
+            state,
+            uptime,
+            restarts: entry.starts.saturating_sub(1),
+            last_error: entry.last_error.clone(),
+        })
+    }
+
+    /// Concrete service registered under `name`, `None` if missing or not a `T`
+    pub fn get<T: Any>(&self, name: &str) -> Option<&T> {
+        let i = self.find(name)?;
+        self.services[i].service.service().as_any().downcast_ref()
+    }
+
+    pub fn get_mut<T: Any>(&mut self, name: &str) -> Option<&mut T> {
+        let i = self.find(name)?;
+        self.services[i]
+            .service
+            .service_mut()
+            .as_any_mut()
+            .downcast_mut()
+    }
+
+    /// Service registered under `name`, without knowing its concrete type
+    pub(crate) fn service_mut(&mut self, name: &str) -> Option<&mut dyn DynService> {
+        let i = self.find(name)?;
+        Some(self.services[i].service.service_mut())
+    }
+
+    pub fn start(&mut self, name: &str) -> Result<(), Error> {
+        let i = self.index(name)?;
+        let entry = &mut self.services[i];
+        entry.starts += 1;
+        let service = &mut entry.service;
+        if let Err(e) = self.events.observe_start(name, || service.start()) {
+            entry.last_error = Some(message(&e));
+            return Err(e);
+        }
+        entry.started_at = Some(self.clock.now());
+        self.started.push(name.to_owned());
+        Ok(())
+    }
+
+    pub fn stop(&mut self, name: &str) -> Result<(), Error> {
+        let i = self.index(name)?;
+        // Service is no longer running even if stopping it failed
+        self.started.retain(|n| n != name);
+        let entry = &mut self.services[i];
+        let service = &mut entry.service;
+        let res = self.events.observe_stop(name, || service.stop());
+        if let Err(e) = &res {
+            entry.last_error = Some(message(e));
+        }
+        res
+    }
+
+    /// Stops the service if it is running, then starts it again
+    pub fn restart(&mut self, name: &str) -> Result<(), Error> {
+        let i = self.index(name)?;
+        if self.services[i].service.state() == &State::Running {
+            self.stop(name)?;
+        }
+        self.start(name)
+    }
+
+    /// Starts every service which is not already running, in registration order
+    pub fn start_all(&mut self) -> BTreeMap<String, Result<(), Error>> {
+        let names: Vec<String> = self
+            .services
+            .iter()
+            .filter(|e| e.service.state() != &State::Running)
+            .map(|e| e.name.clone())
+            .collect();
+        names
+            .into_iter()
+            .map(|n| {
+                let res = self.start(&n);
+                (n, res)
+            })
+            .collect()
+    }
+
+    /// Stops every running service, in the reverse order they were started
+    pub fn stop_all(&mut self) -> BTreeMap<String, Result<(), Error>> {
+        let mut results = BTreeMap::new();
+        while let Some(n) = self.started.last().cloned() {
+            let res = self.stop(&n);
+            results.insert(n, res);
+        }
+        results
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.services.iter().position(|e| e.name == name)
+    }
+
+    fn index(&self, name: &str) -> Result<usize, Error> {
+        self.find(name)
+            .ok_or_else(|| Error::UnknownService(name.to_owned()))
+    }
+}
+
+impl Default for ServiceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ServiceOne, ServiceThree, ServiceTwo};
+
+    #[test]
+    fn start_by_name() {
+        let mut reg = ServiceRegistry::new();
+        reg.register("two", ServiceTwo).unwrap();
+        reg.register("one", ServiceOne).unwrap();
+        reg.register("three", ServiceThree { fails: true }).unwrap();
+        assert_eq!(
+            reg.register("one", ServiceOne),
+            Err(Error::DuplicateService("one".to_owned()))
+        );
+
+        let results = reg.start_all();
+        assert_eq!(results["one"], Ok(()));
+        assert_eq!(
+            results["two"],
+            Err(Error::Custom("Service two failed!".to_owned()))
+        );
+        assert!(results["three"].is_err());
+
+        // Concrete type can be reached to fix the service and try again
+        reg.get_mut::<ServiceThree>("three").unwrap().fails = false;
+        assert!(reg.get::<ServiceOne>("three").is_none());
+        assert_eq!(reg.start("three"), Ok(()));
+        assert_eq!(reg.state("three"), Some(&State::Running));
+
+        let bus = EventBus::new();
+        let rx = bus.channel();
+        reg.events = bus;
+        let stopped = reg.stop_all();
+        assert_eq!(stopped.keys().collect::<Vec<_>>(), vec!["one", "three"]);
+        let events: Vec<_> = rx.try_iter().map(|e| e.service).collect();
+        assert_eq!(events, vec!["three", "three", "one", "one"]);
+        assert_eq!(reg.state("one"), Some(&State::Stopped));
+    }
+
+    #[test]
+    fn add_and_remove_at_runtime() {
+        let mut reg = ServiceRegistry::new();
+        reg.register("one", ServiceOne).unwrap();
+        reg.start("one").unwrap();
+
+        reg.register("three", ServiceThree { fails: false })
+            .unwrap();
+        assert_eq!(reg.names().collect::<Vec<_>>(), vec!["one", "three"]);
+
+        let removed = reg.deregister("one").unwrap();
+        assert!(removed.as_any().is::<ServiceOne>());
+        assert!(!reg.contains("one"));
+        assert_eq!(
+            reg.start("one"),
+            Err(Error::UnknownService("one".to_owned()))
+        );
+        // Removed service was stopped, so there is nothing left to stop
+        assert!(reg.stop_all().is_empty());
+    }
+
+    #[test]
+    fn status_and_restart() {
+        let mut reg = ServiceRegistry::new();
+        reg.register("three", ServiceThree { fails: true }).unwrap();
+        assert!(reg.start("three").is_err());
+        reg.get_mut::<ServiceThree>("three").unwrap().fails = false;
+        reg.start("three").unwrap();
+        reg.restart("three").unwrap();
+
+        let status = reg.status("three").unwrap();
+        assert_eq!(status.state, State::Running);
+        assert!(status.uptime.is_some());
+        assert_eq!(status.restarts, 2);
+        assert_eq!(
+            status.last_error,
+            Some("Failed to start service".to_owned())
+        );
+
+        reg.stop("three").unwrap();
+        assert_eq!(reg.status("three").unwrap().uptime, None);
+        assert_eq!(
+            reg.restart("missing"),
+            Err(Error::UnknownService("missing".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,494 @@
+use crate::config::{self, Entry};
+use crate::{
+    dependency_order, DynService, Error, EventBus, ServiceFactory, ServiceRegistry, State,
+};
+use std::path::Path;
+
+/// Services which changed in a successful `ConfiguredServices::reload`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Built again from the new config, and restarted if the old service was running
+    pub replaced: Vec<String>,
+    /// Handed the new config in place, without being stopped
+    pub reconfigured: Vec<String>,
+    /// Running services which depend on a replaced service, stopped before it and started
+    /// again after it
+    pub restarted: Vec<String>,
+}
+
+/// Single step of a reload, kept so it can be undone
+enum Step {
+    Stopped(String),
+    Removed(String, Box<dyn DynService>),
+    Replaced(String, Box<dyn DynService>),
+    Added(String),
+    Reconfigured(String),
+    Started(String),
+}
+
+/// Everything a reload is going to change, worked out before anything is changed
+struct Plan<'a> {
+    file: &'a str,
+    text: &'a str,
+    entries: Vec<Entry>,
+    order: Vec<String>,
+    summary: ReloadSummary,
+    /// New services, for the names in `summary.added` and `summary.replaced`
+    built: Vec<(String, Box<dyn DynService>)>,
+}
+
+/// Services built from a config document, which can be reloaded from a new version of the
+/// document while they run
+pub struct ConfiguredServices {
+    factory: ServiceFactory,
+    registry: ServiceRegistry,
+    /// Name and text of the current document, kept to reconfigure services back on rollback
+    file: String,
+    text: String,
+    entries: Vec<Entry>,
+    /// Names in dependency order
+    order: Vec<String>,
+    /// Whether services are meant to be running, so added services are started on reload
+    running: bool,
+}
+
+impl ConfiguredServices {
+    /// Empty set of services, which `reload` fills in
+    pub fn new(factory: ServiceFactory) -> Self {
+        Self {
+            factory,
+            registry: ServiceRegistry::new(),
+            file: String::new(),
+            text: String::new(),
+            entries: Vec::new(),
+            order: Vec::new(),
+            running: false,
+        }
+    }
+
+    /// Bus to report every start and stop to
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.registry = self.registry.with_events(events);
+        self
+    }
+
+    pub fn registry(&self) -> &ServiceRegistry {
+        &self.registry
+    }
+
+    /// Names of the services, in the order they are started
+    pub fn order(&self) -> impl Iterator<Item = &str> {
+        self.order.iter().map(String::as_str)
+    }
+
+    /// Starts every service which is not running, in dependency order. Stops at the first
+    /// service which fails to start
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.running = true;
+        for name in &self.order {
+            if self.registry.state(name) != Some(&State::Running) {
+                self.registry.start(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops every running service in reverse dependency order, returning the first error
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.running = false;
+        let mut res = Ok(());
+        for name in self.order.iter().rev() {
+            if self.registry.state(name) == Some(&State::Running) {
+                let stopped = self.registry.stop(name);
+                res = res.and(stopped);
+            }
+        }
+        res
+    }
+
+    /// Applies a new version of the config. Services whose entry did not change are left
+    /// alone, and a change to `depends_on` alone only changes the order. Services of a kind
+    /// registered with `register_reconfigurable` are reconfigured in place, any other changed
+    /// service is built again and restarted if it was running, along with every running
+    /// service which depends on it. Added services are started if the set is running, any
+    /// other service which is not running is left stopped.
+    ///
+    /// All or nothing: if any step fails, every step already taken is undone and the error
+    /// holds the cause along with anything which could not be undone
+    pub fn reload(&mut self, file: &str, text: &str) -> Result<ReloadSummary, Error> {
+        let entries = self.factory.parse(file, text)?;
+        let order: Vec<String> = dependency_order(
+            entries
+                .iter()
+                .map(|e| (e.name.as_str(), e.depends_on.as_slice())),
+        )?
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+
+        // Every service is built before anything is touched, so a bad entry changes nothing
+        let mut summary = ReloadSummary::default();
+        let mut built = Vec::new();
+        for entry in &entries {
+            match self.entries.iter().find(|e| e.name == entry.name) {
+                Some(old) if old.same_service(entry) => {}
+                Some(old) if old.kind == entry.kind && self.factory.can_reconfigure(&old.kind) => {
+                    summary.reconfigured.push(entry.name.clone())
+                }
+                old => {
+                    built.push((entry.name.clone(), self.factory.build(file, text, entry)?));
+                    match old {
+                        Some(_) => summary.replaced.push(entry.name.clone()),
+                        None => summary.added.push(entry.name.clone()),
+                    }
+                }
+            }
+        }
+        summary.removed = self
+            .entries
+            .iter()
+            .filter(|old| !entries.iter().any(|e| e.name == old.name))
+            .map(|old| old.name.clone())
+            .collect();
+        // Dependencies come first in the order, so a single pass finds every service which
+        // depends on a replaced one through others
+        for name in &self.order {
+            let entry = self
+                .entries
+                .iter()
+                .find(|e| &e.name == name)
+                .expect("entry");
+            let depends_on_replaced = entry
+                .depends_on
+                .iter()
+                .any(|d| summary.replaced.contains(d) || summary.restarted.contains(d));
+            if depends_on_replaced
+                && !summary.removed.contains(name)
+                && !summary.replaced.contains(name)
+            {
+                summary.restarted.push(name.clone());
+            }
+        }
+        summary
+            .restarted
+            .retain(|name| self.registry.state(name) == Some(&State::Running));
+
+        let mut plan = Plan {
+            file,
+            text,
+            entries,
+            order,
+            summary,
+            built,
+        };
+        let mut steps = Vec::new();
+        if let Err((service, e)) = self.apply(&mut plan, &mut steps) {
+            let rollback = self.undo(steps);
+            return Err(Error::ReloadFailed {
+                service,
+                cause: e.to_string(),
+                rollback,
+            });
+        }
+        self.file = file.to_owned();
+        self.text = text.to_owned();
+        self.entries = plan.entries;
+        self.order = plan.order;
+        Ok(plan.summary)
+    }
+
+    /// Reads a new version of the config from a file, then reloads it
+    pub fn reload_file<P: AsRef<Path>>(&mut self, path: P) -> Result<ReloadSummary, Error> {
+        let (file, text) = config::read(path.as_ref())?;
+        self.reload(&file, &text)
+    }
+
+    /// Takes every step of a reload, recording each in `steps`. Errors with the name of the
+    /// service whose step failed
+    fn apply(&mut self, plan: &mut Plan<'_>, steps: &mut Vec<Step>) -> Result<(), (String, Error)> {
+        let summary = &plan.summary;
+        // Services which go away or restart are stopped before the services they depend on
+        for name in self.order.iter().rev() {
+            let leaving = summary.removed.contains(name)
+                || summary.replaced.contains(name)
+                || summary.restarted.contains(name);
+            if leaving && self.registry.state(name) == Some(&State::Running) {
+                self.registry.stop(name).map_err(at(name))?;
+                steps.push(Step::Stopped(name.clone()));
+            }
+        }
+        for name in &summary.removed {
+            let old = self.registry.deregister(name).map_err(at(name))?;
+            steps.push(Step::Removed(name.clone(), old));
+        }
+        for (name, service) in plan.built.drain(..) {
+            if summary.replaced.contains(&name) {
+                let old = self.registry.replace(&name, service).map_err(at(&name))?;
+                steps.push(Step::Replaced(name, old));
+            } else {
+                self.registry
+                    .register_boxed(&name, service)
+                    .map_err(at(&name))?;
+                steps.push(Step::Added(name));
+            }
+        }
+        for name in &summary.reconfigured {
+            let entry = plan
+                .entries
+                .iter()
+                .find(|e| &e.name == name)
+                .expect("entry");
+            let service = self.registry.service_mut(name).expect("registered");
+            // Recorded first, since a hook which fails may have applied part of the change
+            steps.push(Step::Reconfigured(name.clone()));
+            self.factory
+                .reconfigure(plan.file, plan.text, entry, service)
+                .map_err(at(name))?;
+        }
+
+        if self.running {
+            let summary = &plan.summary;
+            for name in &plan.order {
+                let starting = summary.added.contains(name)
+                    || summary.replaced.contains(name)
+                    || summary.restarted.contains(name);
+                if starting && self.registry.state(name) != Some(&State::Running) {
+                    self.registry.start(name).map_err(at(name))?;
+                    steps.push(Step::Started(name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes `steps` in reverse, returning the name and error of each step that failed
+    fn undo(&mut self, steps: Vec<Step>) -> Vec<(String, String)> {
+        let mut errors = Vec::new();
+        for step in steps.into_iter().rev() {
+            let (name, res) = match step {
+                Step::Started(name) => {
+                    let res = self.registry.stop(&name);
+                    (name, res)
+                }
+                Step::Reconfigured(name) => {
+                    let old = self.entries.iter().find(|e| e.name == name).expect("entry");
+                    let service = self.registry.service_mut(&name).expect("registered");
+                    let res = self
+                        .factory
+                        .reconfigure(&self.file, &self.text, old, service);
+                    (name, res)
+                }
+                Step::Added(name) => {
+                    let res = self.registry.deregister(&name).map(drop);
+                    (name, res)
+                }
+                Step::Replaced(name, old) => {
+                    let res = self.registry.replace(&name, old).map(drop);
+                    (name, res)
+                }
+                Step::Removed(name, old) => {
+                    let res = self.registry.register_boxed(&name, old);
+                    (name, res)
+                }
+                Step::Stopped(name) => {
+                    let res = self.registry.start(&name);
+                    (name, res)
+                }
+            };
+            if let Err(e) = res {
+                errors.push((name, e.to_string()));
+            }
+        }
+        errors
+    }
+}
+
+/// Tags an error with the service it came from
+fn at(name: &str) -> impl FnOnce(Error) -> (String, Error) + '_ {
+    move |e| (name.to_owned(), e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EventKind, ServiceOne, ServiceThree};
+
+    const V1: &str = r#"
+[[service]]
+name = "db"
+kind = "one"
+
+[[service]]
+name = "flaky"
+kind = "three"
+depends_on = ["db"]
+
+[[service]]
+name = "cache"
+kind = "one"
+"#;
+
+    fn running(file: &str, text: &str) -> (ConfiguredServices, EventBus) {
+        let bus = EventBus::new();
+        let mut set =
+            ConfiguredServices::new(ServiceFactory::with_builtins()).with_events(bus.clone());
+        set.reload(file, text).unwrap();
+        set.start().unwrap();
+        (set, bus)
+    }
+
+    fn fails(set: &ConfiguredServices) -> Option<bool> {
+        set.registry().get::<ServiceThree>("flaky").map(|s| s.fails)
+    }
+
+    #[test]
+    fn only_changed_services_restart() {
+        let (mut set, bus) = running("v1.toml", V1);
+        let rx = bus.channel();
+        let v2 = r#"
+[[service]]
+name = "db"
+kind = "one"
+
+[[service]]
+name = "flaky"
+kind = "three"
+depends_on = ["db"]
+fails = true
+
+[[service]]
+name = "cache"
+kind = "three"
+
+[[service]]
+name = "queue"
+kind = "one"
+depends_on = ["cache"]
+"#;
+        let summary = set.reload("v2.toml", v2).unwrap();
+        assert_eq!(
+            summary,
+            ReloadSummary {
+                added: vec!["queue".to_owned()],
+                removed: vec![],
+                replaced: vec!["cache".to_owned()],
+                reconfigured: vec!["flaky".to_owned()],
+                restarted: vec![],
+            }
+        );
+        assert_eq!(fails(&set), Some(true));
+        assert!(set.registry().get::<ServiceThree>("cache").is_some());
+        let events: Vec<_> = rx
+            .try_iter()
+            .filter(|e| e.kind == EventKind::Stopped || e.kind == EventKind::Started)
+            .map(|e| (e.service, e.kind))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("cache".to_owned(), EventKind::Stopped),
+                ("cache".to_owned(), EventKind::Started),
+                ("queue".to_owned(), EventKind::Started),
+            ]
+        );
+
+        // Dropping services stops and removes them
+        let summary = set.reload("v1.toml", V1).unwrap();
+        assert_eq!(summary.removed, vec!["queue".to_owned()]);
+        assert!(!set.registry().contains("queue"));
+        assert_eq!(fails(&set), Some(false));
+        assert_eq!(
+            set.order().collect::<Vec<_>>(),
+            vec!["db", "flaky", "cache"]
+        );
+    }
+
+    #[test]
+    fn failed_reload_rolls_back() {
+        let (mut set, _) = running("v1.toml", V1);
+        // `two` always fails to start, after every other step has been taken
+        let bad = r#"
+[[service]]
+name = "db"
+kind = "two"
+
+[[service]]
+name = "flaky"
+kind = "three"
+depends_on = ["db"]
+fails = true
+"#;
+        assert_eq!(
+            set.reload("bad.toml", bad),
+            Err(Error::ReloadFailed {
+                service: "db".to_owned(),
+                cause: "Custom Service error: Service two failed!".to_owned(),
+                rollback: vec![],
+            })
+        );
+        assert_eq!(fails(&set), Some(false));
+        assert!(set.registry().get::<ServiceOne>("db").is_some());
+        for name in &["db", "flaky", "cache"] {
+            assert_eq!(set.registry().state(name), Some(&State::Running));
+        }
+
+        // Bad documents are rejected before anything changes
+        let cycle = "[[service]]\nname = \"a\"\nkind = \"one\"\ndepends_on = [\"a\"]\n";
+        assert_eq!(
+            set.reload("cycle.toml", cycle),
+            Err(Error::DependencyCycle(vec!["a".to_owned(), "a".to_owned()]))
+        );
+        let bad_field = "[[service]]\nname = \"flaky\"\nkind = \"three\"\nfails = 1\n";
+        assert!(set.reload("bad.toml", bad_field).is_err());
+        assert_eq!(
+            set.order().collect::<Vec<_>>(),
+            vec!["db", "flaky", "cache"]
+        );
+    }
+
+    #[test]
+    fn dependents_of_replaced_services_restart() {
+        let (mut set, bus) = running("v1.toml", V1);
+        let rx = bus.channel();
+        let v2 = V1.replacen(
+            "name = \"db\"\nkind = \"one\"",
+            "name = \"db\"\nkind = \"three\"",
+            1,
+        );
+        let summary = set.reload("v2.toml", &v2).unwrap();
+        assert_eq!(summary.replaced, vec!["db".to_owned()]);
+        assert_eq!(summary.restarted, vec!["flaky".to_owned()]);
+        let events: Vec<_> = rx
+            .try_iter()
+            .filter(|e| e.kind == EventKind::Stopped || e.kind == EventKind::Started)
+            .map(|e| (e.service, e.kind))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("flaky".to_owned(), EventKind::Stopped),
+                ("db".to_owned(), EventKind::Stopped),
+                ("db".to_owned(), EventKind::Started),
+                ("flaky".to_owned(), EventKind::Started),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_added_services_start() {
+        let mut set = ConfiguredServices::new(ServiceFactory::with_builtins());
+        let v1 = "[[service]]\nname = \"db\"\nkind = \"one\"\n\n\
+                  [[service]]\nname = \"broken\"\nkind = \"two\"\n";
+        set.reload("v1.toml", v1).unwrap();
+        assert!(set.start().is_err());
+
+        // The service which failed to start is left alone
+        let v2 = format!("{}\n[[service]]\nname = \"queue\"\nkind = \"one\"\n", v1);
+        let summary = set.reload("v2.toml", &v2).unwrap();
+        assert_eq!(summary.added, vec!["queue".to_owned()]);
+        assert_eq!(set.registry().state("queue"), Some(&State::Running));
+        assert_ne!(set.registry().state("broken"), Some(&State::Running));
+    }
+}
@@ -0,0 +1,711 @@
+use crate::parallel::panic_message;
+use crate::{system_clock, Clock, Error, Health, StartableService};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest the scheduler sleeps before checking if it was stopped
+const TICK: Duration = Duration::from_millis(10);
+const SECS_PER_DAY: u64 = 86_400;
+/// Cron specs which match nothing this many days ahead, such as 30 February, never run
+const SEARCH_DAYS: u64 = 366 * 5;
+
+/// Five field cron expression: minute, hour, day of month, month and day of week, in UTC.
+/// Fields take `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps (`*/10`, `8-18/2`).
+/// Like cron, when both days are restricted a day matching either one is used
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let invalid = |reason: String| Error::InvalidSchedule {
+            spec: spec.to_owned(),
+            reason,
+        };
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid(format!(
+                "expected 5 fields, found {}",
+                fields.len()
+            )));
+        }
+        let field = |i: usize, name: &str, min: u64, max: u64| {
+            parse_field(fields[i], min, max)
+                .map_err(|e| invalid(format!("bad {} field `{}`: {}", name, fields[i], e)))
+        };
+        let mut weekdays = field(4, "day of week", 0, 7)?;
+        // 7 is another name for Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: field(0, "minute", 0, 59)?,
+            hours: field(1, "hour", 0, 23)?,
+            days: field(2, "day of month", 1, 31)?,
+            months: field(3, "month", 1, 12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// First matching minute strictly after `after`
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let secs = after.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let start = (secs / 60 + 1) * 60;
+        let first_day = start / SECS_PER_DAY;
+        for day in first_day..first_day + SEARCH_DAYS {
+            let (_, month, dom) = civil_from_days(day);
+            // 1 January 1970 was a Thursday
+            let weekday = (day + 4) % 7;
+            if !self.day_matches(month, dom, weekday) {
+                continue;
+            }
+            let from = if day == first_day {
+                (start % SECS_PER_DAY) / 60
+            } else {
+                0
+            };
+            let matches =
+                |m: &u64| self.hours & (1 << (m / 60)) != 0 && self.minutes & (1 << (m % 60)) != 0;
+            let minute = (from..24 * 60).find(matches);
+            if let Some(m) = minute {
+                return Some(UNIX_EPOCH + Duration::from_secs(day * SECS_PER_DAY + m * 60));
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, month: u64, dom: u64, weekday: u64) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << dom) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+/// Bit set of the values matched by a single cron field
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let step = match step {
+            Some(s) => s.parse().map_err(|_| format!("bad step `{}`", s))?,
+            None => 1,
+        };
+        if step == 0 {
+            return Err("step must be at least 1".to_owned());
+        }
+        let number = |s: &str| -> Result<u64, String> {
+            match s.parse() {
+                Ok(n) if (min..=max).contains(&n) => Ok(n),
+                _ => Err(format!("`{}` is not between {} and {}", s, min, max)),
+            }
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            // A single value with a step runs from that value to the end
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if from > to {
+            return Err(format!("range `{}` is backwards", range));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+/// Year, month and day of the given day since 1970, from Howard Hinnant's date algorithms
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Time between runs of a `Schedule::Every`, never zero. Built with `Schedule::every`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval(Duration);
+
+impl Interval {
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+/// When a `ScheduledService` runs its job
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    /// Every interval, with the first run one interval after the service starts
+    Every(Interval),
+    Cron(Cron),
+}
+
+impl Schedule {
+    pub fn every(interval: Duration) -> Result<Self, Error> {
+        if interval == Duration::from_secs(0) {
+            return Err(Error::InvalidSchedule {
+                spec: format!("{:?}", interval),
+                reason: "interval must not be zero".to_owned(),
+            });
+        }
+        Ok(Schedule::Every(Interval(interval)))
+    }
+
+    pub fn cron(spec: &str) -> Result<Self, Error> {
+        Cron::parse(spec).map(Schedule::Cron)
+    }
+
+    /// Next run time after `after`, `None` if the schedule never runs again
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Every(interval) => Some(after + interval.0),
+            Schedule::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+/// What happens to runs which are due while the previous run is still going
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overlap {
+    /// Runs are recorded as skipped
+    Skip,
+    /// Runs wait for the previous run, then run one after another
+    Queue,
+    /// Runs start straight away on their own thread
+    Concurrent,
+}
+
+/// Which missed runs are made up for. Runs are missed when their due time passes while the
+/// service is stopped, or while the scheduler was not running. The latest due run always runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchUp {
+    /// Missed runs are only recorded as missed
+    Skip,
+    /// Every missed run is run
+    All,
+    /// Up to this many of the most recent missed runs are run
+    Limit(u32),
+}
+
+/// How a single scheduled run went
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    Ok,
+    /// Job returned an error or panicked, holds the message
+    Failed(String),
+    /// Not run since the previous run was still going
+    Skipped,
+    /// Not run since it was missed and not caught up
+    Missed,
+}
+
+/// Entry in the run history of a `ScheduledService`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunRecord {
+    /// Time the run was scheduled for
+    pub due: SystemTime,
+    /// Time the run actually started, `None` if it never ran
+    pub started: Option<SystemTime>,
+    pub duration: Duration,
+    pub outcome: RunOutcome,
+}
+
+type Job = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+struct History {
+    records: VecDeque<RunRecord>,
+    capacity: usize,
+    /// Due time of the latest run handled, so runs missed while stopped can be caught up
+    last_due: Option<SystemTime>,
+}
+
+impl History {
+    fn push(&mut self, record: RunRecord) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    fn not_run(&mut self, due: SystemTime, outcome: RunOutcome) {
+        self.push(RunRecord {
+            due,
+            started: None,
+            duration: Duration::from_secs(0),
+            outcome,
+        });
+    }
+}
+
+/// Runs a job on a cron or interval schedule while the service is running. The job runs on a
+/// background thread, stopping the service waits for any run in progress to finish
+pub struct ScheduledService {
+    schedule: Schedule,
+    job: Job,
+    overlap: Overlap,
+    catch_up: CatchUp,
+    clock: Arc<dyn Clock>,
+    history: Arc<Mutex<History>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl ScheduledService {
+    /// Service which skips overlapping and missed runs, keeping the last 100 runs
+    pub fn new<F>(schedule: Schedule, job: F) -> Self
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        Self {
+            schedule,
+            job: Arc::new(job),
+            overlap: Overlap::Skip,
+            catch_up: CatchUp::Skip,
+            clock: system_clock(),
+            history: Arc::new(Mutex::new(History {
+                records: VecDeque::new(),
+                capacity: 100,
+                last_due: None,
+            })),
+            stop: Arc::new(AtomicBool::new(false)),
+            worker: None,
+        }
+    }
+
+    pub fn with_overlap(mut self, overlap: Overlap) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    pub fn with_catch_up(mut self, catch_up: CatchUp) -> Self {
+        self.catch_up = catch_up;
+        self
+    }
+
+    /// Number of runs kept in the history (default 100)
+    pub fn with_history(self, capacity: usize) -> Self {
+        self.lock().capacity = capacity.max(1);
+        self
+    }
+
+    /// Clock the schedule is followed on
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Last `n` runs, oldest first
+    pub fn history(&self, n: usize) -> Vec<RunRecord> {
+        let history = self.lock();
+        let skip = history.records.len().saturating_sub(n);
+        history.records.iter().skip(skip).cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, History> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StartableService for ScheduledService {
+    /// Starts following the schedule. Runs missed since the service was last stopped are
+    /// handled based on the catch up rule
+    fn start(&mut self) -> Result<(), String> {
+        if self.worker.is_some() {
+            return Err("scheduled service is already running".to_owned());
+        }
+        self.stop.store(false, Ordering::SeqCst);
+        let worker = Worker {
+            schedule: self.schedule.clone(),
+            job: self.job.clone(),
+            overlap: self.overlap,
+            catch_up: self.catch_up,
+            clock: self.clock.clone(),
+            history: self.history.clone(),
+            stop: self.stop.clone(),
+            concurrent: Vec::new(),
+        };
+        self.worker = Some(thread::spawn(move || worker.run()));
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), String> {
+        let worker = match self.worker.take() {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        self.stop.store(true, Ordering::SeqCst);
+        worker
+            .join()
+            .map_err(|_| "scheduler thread panicked".to_owned())
+    }
+
+    /// Unhealthy while the latest run failed
+    fn health(&self) -> Health {
+        let history = self.lock();
+        match history.records.iter().rev().find(|r| r.started.is_some()) {
+            Some(RunRecord {
+                outcome: RunOutcome::Failed(e),
+                ..
+            }) => Health::Unhealthy(e.clone()),
+            _ => Health::Healthy,
+        }
+    }
+}
+
+impl Drop for ScheduledService {
+    /// Only tells the scheduler to stop, without waiting for it
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Scheduler loop, running on its own thread while the service is started
+struct Worker {
+    schedule: Schedule,
+    job: Job,
+    overlap: Overlap,
+    catch_up: CatchUp,
+    clock: Arc<dyn Clock>,
+    history: Arc<Mutex<History>>,
+    stop: Arc<AtomicBool>,
+    concurrent: Vec<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let last_due = self.lock().last_due;
+        let mut next = self
+            .schedule
+            .next_after(last_due.unwrap_or_else(|| self.clock.system_time()));
+        while !self.stop.load(Ordering::SeqCst) {
+            self.concurrent.retain(|h| !h.is_finished());
+            let now = self.clock.system_time();
+            let due = self.take_due(&mut next, now);
+            if due.is_empty() {
+                let wait = next
+                    .and_then(|n| n.duration_since(now).ok())
+                    .map_or(TICK, |d| d.min(TICK));
+                self.clock.sleep(wait);
+                continue;
+            }
+
+            let missed = due.len() - 1;
+            let kept = match self.catch_up {
+                CatchUp::Skip => 0,
+                CatchUp::All => missed,
+                CatchUp::Limit(n) => missed.min(n as usize),
+            };
+            for &t in &due[..missed - kept] {
+                self.lock().not_run(t, RunOutcome::Missed);
+            }
+            let mut pending: VecDeque<SystemTime> = due[missed - kept..].iter().copied().collect();
+            while let Some(t) = pending.pop_front() {
+                if self.stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                if self.overlap == Overlap::Concurrent {
+                    let (job, clock, history) =
+                        (self.job.clone(), self.clock.clone(), self.history.clone());
+                    self.concurrent.push(thread::spawn(move || {
+                        let record = run_job(&job, &*clock, t);
+                        history
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .push(record);
+                    }));
+                    continue;
+                }
+                let record = run_job(&self.job, &*self.clock, t);
+                self.lock().push(record);
+                // Runs which became due while this one was going overlap with it
+                let during = self.take_due(&mut next, self.clock.system_time());
+                match self.overlap {
+                    Overlap::Queue => pending.extend(during),
+                    _ => {
+                        for t in during {
+                            self.lock().not_run(t, RunOutcome::Skipped);
+                        }
+                    }
+                }
+            }
+        }
+        for handle in self.concurrent.drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    /// Every due time up to `now`, moving `next` past them
+    fn take_due(&mut self, next: &mut Option<SystemTime>, now: SystemTime) -> Vec<SystemTime> {
+        let mut due = Vec::new();
+        while let Some(t) = *next {
+            if t > now {
+                break;
+            }
+            due.push(t);
+            // Schedule which does not move forward would be due forever, so it stops instead
+            *next = self.schedule.next_after(t).filter(|&n| n > t);
+        }
+        if let Some(&last) = due.last() {
+            self.lock().last_due = Some(last);
+        }
+        due
+    }
+
+    fn lock(&self) -> MutexGuard<'_, History> {
+        self.history.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn run_job(job: &Job, clock: &dyn Clock, due: SystemTime) -> RunRecord {
+    let started = clock.system_time();
+    let at = clock.now();
+    let outcome = match panic::catch_unwind(AssertUnwindSafe(|| job())) {
+        Ok(Ok(())) => RunOutcome::Ok,
+        Ok(Err(e)) => RunOutcome::Failed(e),
+        Err(payload) => RunOutcome::Failed(format!("job panicked: {}", panic_message(&*payload))),
+    };
+    RunRecord {
+        due,
+        started: Some(started),
+        duration: clock.now() - at,
+        outcome,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SimClock;
+    use std::sync::atomic::AtomicU32;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn cron_next_run() {
+        // Friday 5 January 2024 17:50 to Monday 8 January 09:00
+        let weekdays = Schedule::cron("*/15 9-17 * * 1-5").unwrap();
+        assert_eq!(
+            weekdays.next_after(at(1_704_477_000)),
+            Some(at(1_704_704_400))
+        );
+        // 13th of the month or any Friday, from Monday 1 January 2024 to Friday 5 January
+        let either = Schedule::cron("0 0 13 * 5").unwrap();
+        assert_eq!(
+            either.next_after(at(1_704_067_200)),
+            Some(at(1_704_412_800))
+        );
+        // From 1 March 2023 to the next leap day, 29 February 2024
+        let leap = Schedule::cron("0 0 29 2 *").unwrap();
+        assert_eq!(leap.next_after(at(1_677_628_800)), Some(at(1_709_164_800)));
+        assert_eq!(
+            Schedule::cron("0 0 30 2 *").unwrap().next_after(at(0)),
+            None
+        );
+        assert_eq!(
+            Schedule::cron("0 0 * * 7").unwrap(),
+            Schedule::cron("0 0 * * 0").unwrap()
+        );
+
+        for bad in [
+            "60 * * * *",
+            "* * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(
+                matches!(Schedule::cron(bad), Err(Error::InvalidSchedule { .. })),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            Schedule::cron("* 24 * * *").unwrap_err().to_string(),
+            "Invalid schedule `* 24 * * *`: bad hour field `24`: `24` is not between 0 and 23"
+        );
+        assert!(Schedule::every(Duration::from_secs(0)).is_err());
+        let every = Schedule::every(Duration::from_secs(30)).unwrap();
+        assert!(matches!(&every, Schedule::Every(i) if i.duration() == Duration::from_secs(30)));
+    }
+
+    /// Job which sleeps on `clock` for `hang` during its first run only
+    fn job(clock: &Arc<SimClock>, hang: Duration) -> impl Fn() -> Result<(), String> {
+        let clock = clock.clone();
+        let runs = AtomicU32::new(0);
+        move || {
+            if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                clock.sleep(hang);
+            }
+            Ok(())
+        }
+    }
+
+    /// Stops the service, moving time along until the scheduler notices
+    fn stop(mut service: ScheduledService, clock: &SimClock) -> ScheduledService {
+        let stopping = thread::spawn(move || {
+            service.stop().unwrap();
+            service
+        });
+        while !stopping.is_finished() {
+            clock.advance(TICK);
+            thread::yield_now();
+        }
+        stopping.join().unwrap()
+    }
+
+    fn outcomes(service: &ScheduledService, clock: &SimClock) -> Vec<(u64, RunOutcome)> {
+        let start = clock.system_time() - clock.elapsed();
+        service
+            .history(10)
+            .into_iter()
+            .map(|r| (r.due.duration_since(start).unwrap().as_secs(), r.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_runs() {
+        for overlap in [Overlap::Skip, Overlap::Queue] {
+            let clock = Arc::new(SimClock::manual());
+            let every = Schedule::every(Duration::from_secs(30)).unwrap();
+            let mut s = ScheduledService::new(every, job(&clock, Duration::from_secs(70)))
+                .with_overlap(overlap)
+                .with_clock(clock.clone());
+            s.start().unwrap();
+            clock.wait_for_sleepers(1);
+            // First run hangs until 100s, while runs at 60s and 90s become due
+            clock.advance(Duration::from_secs(30));
+            clock.wait_for_sleepers(1);
+            clock.advance(Duration::from_secs(70));
+            clock.wait_for_sleepers(1);
+
+            let history = s.history(10);
+            assert_eq!(history[0].duration, Duration::from_secs(70));
+            let expected = match overlap {
+                Overlap::Skip => RunOutcome::Skipped,
+                _ => RunOutcome::Ok,
+            };
+            assert_eq!(
+                outcomes(&s, &clock),
+                vec![(30, RunOutcome::Ok), (60, expected.clone()), (90, expected)]
+            );
+            stop(s, &clock);
+        }
+    }
+
+    #[test]
+    fn catch_up_after_restart() {
+        let clock = Arc::new(SimClock::manual());
+        let every = Schedule::every(Duration::from_secs(30)).unwrap();
+        let mut s = ScheduledService::new(every, || Ok(()))
+            .with_catch_up(CatchUp::Limit(1))
+            .with_clock(clock.clone());
+        s.start().unwrap();
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(30));
+        clock.wait_for_sleepers(1);
+        let mut s = stop(s, &clock);
+
+        // Runs at 60s, 90s and 120s are due while stopped, only the latest two run
+        clock.advance_to(Duration::from_secs(130));
+        s.start().unwrap();
+        clock.wait_for_sleepers(1);
+        assert_eq!(
+            outcomes(&s, &clock),
+            vec![
+                (30, RunOutcome::Ok),
+                (60, RunOutcome::Missed),
+                (90, RunOutcome::Ok),
+                (120, RunOutcome::Ok)
+            ]
+        );
+        assert_eq!(s.history(1)[0].started, Some(s.clock.system_time()));
+        stop(s, &clock);
+    }
+
+    #[test]
+    fn concurrent_runs_and_failures() {
+        let clock = Arc::new(SimClock::manual());
+        let every = Schedule::every(Duration::from_secs(30)).unwrap();
+        let mut s = ScheduledService::new(every, job(&clock, Duration::from_secs(45)))
+            .with_overlap(Overlap::Concurrent)
+            .with_clock(clock.clone());
+        s.start().unwrap();
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(30));
+        // Scheduler and the hanging first run
+        clock.wait_for_sleepers(2);
+        clock.advance(Duration::from_secs(30));
+        // Second run starts and finishes while the first one is still going
+        clock.wait_for_sleepers(2);
+        while s.history(10).is_empty() {
+            thread::yield_now();
+        }
+        assert_eq!(outcomes(&s, &clock), vec![(60, RunOutcome::Ok)]);
+        clock.advance(Duration::from_secs(15));
+        while s.history(10).len() < 2 {
+            thread::yield_now();
+        }
+        let s = stop(s, &clock);
+        let history = s.history(10);
+        assert_eq!(history[1].duration, Duration::from_secs(45));
+
+        let clock = Arc::new(SimClock::manual());
+        let mut s = ScheduledService::new(Schedule::every(Duration::from_secs(1)).unwrap(), || {
+            Err("disk full".to_owned())
+        })
+        .with_history(2)
+        .with_clock(clock.clone());
+        s.start().unwrap();
+        assert_eq!(
+            s.start(),
+            Err("scheduled service is already running".to_owned())
+        );
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(3));
+        clock.wait_for_sleepers(1);
+        assert_eq!(s.health(), Health::Unhealthy("disk full".to_owned()));
+        // Only the latest run is made up for, and only two records are kept
+        assert_eq!(
+            outcomes(&s, &clock),
+            vec![
+                (2, RunOutcome::Missed),
+                (3, RunOutcome::Failed("disk full".to_owned()))
+            ]
+        );
+        stop(s, &clock);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{AssocService, Error, StartableService};
+use crate::supervisor::XorShift;
+use crate::{system_clock, AssocService, Clock, Error, StartableService};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// This service will always start
 pub struct ServiceOne;
@@ -33,6 +36,9 @@ impl StartableService for ServiceThree {
             Ok(())
         }
     }
+    fn start_typed(&mut self) -> Result<(), Error> {
+        AssocService::start(self)
+    }
 }
 impl AssocService for ServiceThree {
     type AssocError = Error;
@@ -55,3 +61,105 @@ impl ServiceThree {
         }
     }
 }
+
+/// How a `FaultyService` fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Start attempts 1 to k fail, every later attempt succeeds
+    FailFirst(u32),
+    /// Each start attempt fails with this probability, from 0.0 to 1.0
+    Probability(f64),
+    /// Each start sleeps this long on the service's clock before succeeding
+    Hang(Duration),
+}
+
+/// Generalisation of `ServiceThree` for tests, which fails its starts based on a `Fault`.
+/// Random failures come from a seeded generator, so a seed always fails the same attempts
+pub struct FaultyService {
+    fault: Fault,
+    attempts: u32,
+    rng: XorShift,
+    clock: Arc<dyn Clock>,
+}
+
+impl FaultyService {
+    /// Service with a fixed seed of 0, running on the system clock
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            attempts: 0,
+            rng: XorShift::new(0),
+            clock: system_clock(),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = XorShift::new(seed);
+        self
+    }
+
+    /// Clock a `Hang` sleeps on
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Number of times start was called
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+impl AssocService for FaultyService {
+    type AssocError = Error;
+    fn start(&mut self) -> Result<(), Self::AssocError> {
+        self.attempts += 1;
+        let fails = match &self.fault {
+            Fault::FailFirst(k) => self.attempts <= *k,
+            Fault::Probability(p) => self.rng.next_f64() < *p,
+            Fault::Hang(d) => {
+                self.clock.sleep(*d);
+                false
+            }
+        };
+        if fails {
+            Err(Error::FailedToStart)
+        } else {
+            Ok(())
+        }
+    }
+}
+impl StartableService for FaultyService {
+    fn start(&mut self) -> Result<(), String> {
+        AssocService::start(self).map_err(|e| e.to_string())
+    }
+    fn start_typed(&mut self) -> Result<(), Error> {
+        AssocService::start(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seeded_faults() {
+        let outcomes = |seed| {
+            let mut s = FaultyService::new(Fault::Probability(0.5)).with_seed(seed);
+            (0..20)
+                .map(|_| AssocService::start(&mut s).is_ok())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(outcomes(42), outcomes(42));
+        assert!(outcomes(42).contains(&true) && outcomes(42).contains(&false));
+        let mut never = FaultyService::new(Fault::Probability(0.0));
+        assert!((0..20).all(|_| AssocService::start(&mut never).is_ok()));
+
+        let mut s = FaultyService::new(Fault::FailFirst(2));
+        let results: Vec<_> = (0..3)
+            .map(|_| AssocService::start(&mut s).is_ok())
+            .collect();
+        assert_eq!(results, vec![false, false, true]);
+        assert_eq!(s.attempts(), 3);
+    }
+}
@@ -0,0 +1,484 @@
+use crate::clock::recv_before;
+use crate::parallel::panic_message;
+use crate::{system_clock, Clock, Error, NodeOutcome, ServiceGraph, StartableService};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Number of SIGINT/SIGTERM received since the last `listen`
+#[cfg(unix)]
+static SIGNALS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Write end of the pipe the signal handler wakes the watcher thread through, -1 until the
+/// handlers are installed
+#[cfg(unix)]
+static SIGNAL_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+/// Tokens of the coordinators listening, all cancelled by the next signal
+#[cfg(unix)]
+static LISTENERS: Mutex<Vec<CancellationToken>> = Mutex::new(Vec::new());
+
+/// Exit code used when a second signal forces the process to exit, as a shell would for SIGINT
+#[cfg(unix)]
+const FORCED_EXIT: i32 = 130;
+
+/// Flag shared with every service so long running work can notice shutdown has begun and bail
+/// out. Clones share the same flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<(Mutex<bool>, Condvar)>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        let (cancelled, changed) = &*self.0;
+        *cancelled.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        changed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *(self.0).0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Blocks until the token is cancelled
+    pub fn wait(&self) {
+        let (cancelled, changed) = &*self.0;
+        let mut cancelled = cancelled.lock().unwrap_or_else(|e| e.into_inner());
+        while !*cancelled {
+            cancelled = changed.wait(cancelled).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// How a single service did when stopped by `ShutdownCoordinator::shutdown`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopOutcome {
+    Stopped,
+    Failed(String),
+    /// Stop did not return before the service's deadline, it is left running in the background
+    TimedOut,
+    /// Stop panicked, holds the panic message
+    Panicked(String),
+}
+
+/// Starts a `ServiceGraph` of services, then stops them in reverse dependency order once
+/// shutdown is requested, either by a signal or by cancelling the token. Unlike
+/// `ServiceGraph::stop`, each service only has until its deadline to stop
+pub struct ShutdownCoordinator {
+    graph: ServiceGraph,
+    /// How long each service has to stop
+    deadlines: HashMap<String, Duration>,
+    token: CancellationToken,
+    deadline: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Self {
+            graph: ServiceGraph::new(),
+            deadlines: HashMap::new(),
+            token: CancellationToken::new(),
+            deadline: Duration::from_secs(10),
+            clock: system_clock(),
+        }
+    }
+
+    /// How long each service added afterwards has to stop, 10 seconds by default
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Clock stop deadlines are measured with
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Adds a service which is started after, and stopped before, every service in `deps`.
+    /// `make` is handed the token which is cancelled when shutdown begins
+    pub fn add<S, F>(&mut self, name: &str, make: F, deps: &[&str])
+    where
+        S: StartableService + Send + 'static,
+        F: FnOnce(CancellationToken) -> S,
+    {
+        self.graph
+            .add(name, Box::new(make(self.token.clone())), deps);
+        self.deadlines.insert(name.to_owned(), self.deadline);
+    }
+
+    /// Overrides how long the named service has to stop
+    pub fn set_deadline(&mut self, name: &str, deadline: Duration) -> Result<(), Error> {
+        let entry = self
+            .deadlines
+            .get_mut(name)
+            .ok_or_else(|| Error::UnknownService(name.to_owned()))?;
+        *entry = deadline;
+        Ok(())
+    }
+
+    /// Token cancelled when shutdown begins
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Installs SIGINT and SIGTERM handlers, the first time any coordinator listens. The next
+    /// signal cancels the token of every coordinator listening, and a signal after that exits
+    /// the process straight away without stopping anything. Each call starts counting signals
+    /// again, and a coordinator has to listen again to be cancelled by a later signal
+    #[cfg(unix)]
+    pub fn listen(&self) -> Result<(), Error> {
+        use std::sync::atomic::Ordering;
+
+        let mut listeners = LISTENERS.lock().unwrap_or_else(|e| e.into_inner());
+        if SIGNAL_PIPE.load(Ordering::SeqCst) < 0 {
+            install_handlers()?;
+        }
+        SIGNALS.store(0, Ordering::SeqCst);
+        listeners.push(self.token.clone());
+        Ok(())
+    }
+
+    /// Starts every service once its dependencies have started, with independent services
+    /// started concurrently like `ServiceGraph::start`. Once the token is cancelled no other
+    /// service is started, and the services not started yet are `Cancelled`
+    pub fn start(&mut self) -> Result<Vec<(String, NodeOutcome)>, Error> {
+        let token = &self.token;
+        self.graph.start_unless(&|| token.is_cancelled())
+    }
+
+    /// Blocks until shutdown is requested
+    pub fn wait(&self) {
+        self.token.wait()
+    }
+
+    /// Cancels the token, then stops every running service in reverse dependency order. Each
+    /// stop runs on its own thread, so a service which misses its deadline is left behind
+    /// rather than holding up the rest. Outcomes are returned in the order services stopped
+    pub fn shutdown(&mut self) -> Vec<(String, StopOutcome)> {
+        self.token.cancel();
+        let (clock, deadlines) = (&self.clock, &self.deadlines);
+        self.graph.stop_with(|name, mut service| {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let res = panic::catch_unwind(AssertUnwindSafe(|| service.stop()));
+                // Receiver is gone if the deadline already passed
+                let _ = tx.send((res, service));
+            });
+            let deadline = clock.now() + deadlines[name];
+            match recv_before(&**clock, &rx, deadline) {
+                Ok((Ok(Ok(())), service)) => (Some(service), StopOutcome::Stopped),
+                Ok((Ok(Err(e)), service)) => (Some(service), StopOutcome::Failed(e)),
+                // Service is dropped along with the panic, it can no longer be used safely
+                Ok((Err(payload), _)) => (None, StopOutcome::Panicked(panic_message(&*payload))),
+                Err(RecvTimeoutError::Timeout) => (None, StopOutcome::TimedOut),
+                Err(RecvTimeoutError::Disconnected) => {
+                    unreachable!("stop thread sends before exiting")
+                }
+            }
+        })
+    }
+
+    /// Listens for signals, starts every service, waits for shutdown to be requested and then
+    /// stops them. Services are stopped straight away if one fails to start
+    #[cfg(unix)]
+    pub fn run(&mut self) -> Result<Vec<(String, StopOutcome)>, Error> {
+        self.listen()?;
+        let started = self.start()?;
+        if started.iter().all(|(_, o)| *o == NodeOutcome::Started) {
+            self.wait();
+        }
+        Ok(self.shutdown())
+    }
+}
+
+/// Creates the signal pipe and its watcher thread, then installs the handlers
+#[cfg(unix)]
+fn install_handlers() -> Result<(), Error> {
+    use std::io;
+    use std::sync::atomic::Ordering;
+
+    extern "C" fn on_signal(_: libc::c_int) {
+        if SIGNALS.fetch_add(1, Ordering::SeqCst) > 0 {
+            // SAFETY: `_exit` is async signal safe, unlike `std::process::exit`
+            unsafe { libc::_exit(FORCED_EXIT) }
+        }
+        let byte = 1u8;
+        // SAFETY: `write` is async signal safe, and the write end never blocks. If the pipe is
+        // somehow full a wake up is already pending, so the byte is not needed
+        unsafe {
+            libc::write(
+                SIGNAL_PIPE.load(Ordering::SeqCst),
+                &byte as *const u8 as *const libc::c_void,
+                1,
+            );
+        }
+    }
+
+    let os_error = |what: &str| Error::Custom(format!("{}: {}", what, io::Error::last_os_error()));
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends of the pipe. Both ends are closed on exec, so child
+    // processes do not keep them open, and the write end is made non blocking
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(os_error("Failed to create signal pipe"));
+        }
+        for &fd in &fds {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+    }
+    let read = fds[0];
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+    thread::spawn(move || {
+        let mut byte = 0u8;
+        loop {
+            // SAFETY: reads at most one byte into `byte`
+            let n = unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            if n <= 0 {
+                return;
+            }
+            let listeners =
+                std::mem::take(&mut *LISTENERS.lock().unwrap_or_else(|e| e.into_inner()));
+            for token in listeners {
+                token.cancel();
+            }
+        }
+    });
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only touches atomics and calls `write` and `_exit`, all of which
+        // are allowed inside a signal handler
+        let previous = unsafe {
+            libc::signal(
+                signal,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+        if previous == libc::SIG_ERR {
+            return Err(Error::Custom(format!(
+                "Failed to install handler for signal {}",
+                signal
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SimClock;
+
+    /// Logs each stop as it begins, then hangs or fails if asked to
+    struct Logged {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+        stop: Result<(), String>,
+        hang: bool,
+    }
+    impl StartableService for Logged {
+        fn stop(&mut self) -> Result<(), String> {
+            self.log.lock().unwrap().push(self.name);
+            if self.hang {
+                thread::sleep(Duration::from_secs(60));
+            }
+            self.stop.clone()
+        }
+    }
+
+    fn wait_for_log(log: &Mutex<Vec<&'static str>>, n: usize) {
+        while log.lock().unwrap().len() < n {
+            thread::yield_now();
+        }
+    }
+
+    /// Start which runs until shutdown begins
+    struct Worker(CancellationToken);
+    impl StartableService for Worker {
+        fn start(&mut self) -> Result<(), String> {
+            while !self.0.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err("cancelled".to_owned())
+        }
+    }
+
+    fn logged(
+        name: &'static str,
+        log: &Arc<Mutex<Vec<&'static str>>>,
+        stop: Result<(), String>,
+        hang: bool,
+    ) -> impl FnOnce(CancellationToken) -> Logged {
+        let log = log.clone();
+        move |_| Logged {
+            name,
+            log,
+            stop,
+            hang,
+        }
+    }
+
+    #[test]
+    fn stops_in_reverse_dependency_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let clock = Arc::new(SimClock::manual());
+        let mut coord = ShutdownCoordinator::new()
+            .with_deadline(Duration::from_secs(5))
+            .with_clock(clock.clone());
+        coord.add("app", logged("app", &log, Ok(()), false), &["cache"]);
+        coord.add("cache", logged("cache", &log, Ok(()), true), &["db"]);
+        coord.add("db", logged("db", &log, Err("busy".to_owned()), false), &[]);
+        coord.set_deadline("db", Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            coord.set_deadline("missing", Duration::from_secs(1)),
+            Err(Error::UnknownService("missing".to_owned()))
+        );
+
+        let started = coord.start().unwrap();
+        assert!(started.iter().all(|(_, o)| *o == NodeOutcome::Started));
+
+        let token = coord.token();
+        let shutdown = thread::spawn(move || (coord.shutdown(), coord));
+        // Time only moves a poll at a time once a stop has begun, and only jumps past a
+        // deadline once the hanging cache is stopping, so the services which do return never
+        // miss their deadline
+        let step_until = |done: &dyn Fn() -> bool| {
+            while !done() {
+                if clock.sleepers() > 0 {
+                    clock.advance(Duration::from_millis(1));
+                }
+                thread::yield_now();
+            }
+        };
+        wait_for_log(&log, 1);
+        step_until(&|| log.lock().unwrap().len() == 2);
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(5));
+        wait_for_log(&log, 3);
+        step_until(&|| shutdown.is_finished());
+        let (stopped, mut coord) = shutdown.join().unwrap();
+        assert!(token.is_cancelled());
+        assert_eq!(
+            stopped,
+            vec![
+                ("app".to_owned(), StopOutcome::Stopped),
+                ("cache".to_owned(), StopOutcome::TimedOut),
+                ("db".to_owned(), StopOutcome::Failed("busy".to_owned())),
+            ]
+        );
+        assert_eq!(*log.lock().unwrap(), vec!["app", "cache", "db"]);
+        assert!(coord.shutdown().is_empty());
+    }
+
+    #[test]
+    fn cancelled_start_bails_out() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut coord = ShutdownCoordinator::new();
+        coord.add("db", logged("db", &log, Ok(()), false), &[]);
+        coord.add("worker", Worker, &["db"]);
+        coord.add("app", logged("app", &log, Ok(()), false), &["worker"]);
+
+        let token = coord.token();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            token.cancel();
+        });
+        let started = coord.start().unwrap();
+        canceller.join().unwrap();
+        assert_eq!(
+            started,
+            vec![
+                ("db".to_owned(), NodeOutcome::Started),
+                (
+                    "worker".to_owned(),
+                    NodeOutcome::Failed("cancelled".to_owned())
+                ),
+                ("app".to_owned(), NodeOutcome::Cancelled),
+            ]
+        );
+        // Only the service which started is stopped
+        let stopped = coord.shutdown();
+        assert_eq!(stopped, vec![("db".to_owned(), StopOutcome::Stopped)]);
+    }
+
+    /// Set when `signal_starts_shutdown` runs the test binary again, so that real signals are
+    /// only ever raised in a separate process
+    #[cfg(unix)]
+    const SIGNAL_CHILD: &str = "GENERICS_SIGNAL_CHILD";
+
+    #[cfg(unix)]
+    fn listening(log: &Arc<Mutex<Vec<&'static str>>>) -> ShutdownCoordinator {
+        let mut coord = ShutdownCoordinator::new();
+        coord.add("db", logged("db", log, Ok(()), false), &[]);
+        coord.listen().unwrap();
+        coord.start().unwrap();
+        coord
+    }
+
+    #[cfg(unix)]
+    fn raise_sigterm() {
+        // SAFETY: handlers are installed, so the signal is handled rather than killing the
+        // process, which is only ever a child of the test
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_starts_shutdown() {
+        use std::process::{Command, Stdio};
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        match std::env::var(SIGNAL_CHILD).as_deref() {
+            Ok("once") => {
+                for _ in 0..2 {
+                    // Each listen counts signals again, so a signal after a finished shutdown
+                    // does not force an exit
+                    let mut coord = listening(&log);
+                    raise_sigterm();
+                    coord.wait();
+                    assert_eq!(
+                        coord.shutdown(),
+                        vec![("db".to_owned(), StopOutcome::Stopped)]
+                    );
+                }
+                return;
+            }
+            Ok("twice") => {
+                let coord = listening(&log);
+                raise_sigterm();
+                coord.wait();
+                raise_sigterm();
+                panic!("second signal did not exit");
+            }
+            _ => {}
+        }
+
+        for (mode, code) in [("once", 0), ("twice", FORCED_EXIT)] {
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "shutdown::test::signal_starts_shutdown"])
+                .env(SIGNAL_CHILD, mode)
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert_eq!(status.code(), Some(code), "{} signal", mode);
+        }
+    }
+}
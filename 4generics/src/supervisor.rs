@@ -0,0 +1,798 @@
+use crate::error::message;
+use crate::{system_clock, Clock, Error, EventBus, EventKind, Health, Probe, StartableService};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Decides if and when a supervised service is started again
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestartPolicy {
+    /// Service is only ever started once
+    Never,
+    /// Service is restarted whenever it exits, even cleanly, until it has been restarted the
+    /// supervisor restart limit times in total
+    Always,
+    /// Service is restarted only when it fails, with `max_attempts` start attempts in total,
+    /// whether they fail or the service fails later on. Once they are used up the strategy
+    /// applies, and a service restarted by the strategy has `max_attempts` again
+    OnFailure { max_attempts: u32 },
+    /// Same as `OnFailure`, but waits an exponentially growing delay between attempts
+    Backoff(Backoff),
+}
+
+/// Exponential backoff schedule, delays are `initial * multiplier^n` capped at `max`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    /// Fraction (0.0 to 1.0) of each delay which is randomly taken off. Values outside the
+    /// range are clamped to it, and NaN counts as no jitter
+    pub jitter: f64,
+    pub max_attempts: u32,
+}
+
+impl Backoff {
+    /// Delay before the given retry (first retry is 1), without any jitter applied
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .checked_pow(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |d| d.min(self.max))
+    }
+
+    /// Delay before the given retry with a random part of the jitter taken off
+    pub(crate) fn jittered(&self, retry: u32, rng: &mut XorShift) -> Duration {
+        let delay = self.delay(retry);
+        // * NaN passes through clamp, and would make mul_f64 panic
+        let jitter = if self.jitter.is_nan() {
+            0.0
+        } else {
+            self.jitter.clamp(0.0, 1.0)
+        };
+        delay - delay.mul_f64(jitter * rng.next_f64())
+    }
+}
+
+/// What else is restarted when a service runs out of restart attempts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Only the failed service is given up on, the rest keep running
+    OneForOne,
+    /// Every supervised service is restarted
+    OneForAll,
+    /// The failed service and every service added after it are restarted
+    RestForOne,
+}
+
+/// Current status of a supervised service
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChildStatus {
+    Pending,
+    Running,
+    Exited,
+    /// Service ran out of restart attempts, holds the last error
+    Failed(String),
+}
+
+struct Child {
+    name: String,
+    service: Box<dyn StartableService>,
+    policy: RestartPolicy,
+    /// Consecutive failed start attempts since last successful start
+    attempts: u32,
+    /// Start attempts counted against the policy's total, since it was added or last
+    /// restarted by the strategy
+    spent: u32,
+    restarts: u32,
+    status: ChildStatus,
+    probe: Option<Probe>,
+}
+
+impl Child {
+    /// How much of the policy's total is used up. `Always` counts restarts, every other policy
+    /// counts start attempts
+    fn budget_spent(&self) -> u32 {
+        match self.policy {
+            RestartPolicy::Always => self.spent.saturating_sub(1),
+            _ => self.spent,
+        }
+    }
+}
+
+/// Owns a set of services and restarts them based on each service's `RestartPolicy`
+pub struct Supervisor {
+    children: Vec<Child>,
+    strategy: Strategy,
+    restart_limit: u32,
+    max_escalations: u32,
+    escalations: u32,
+    rng: XorShift,
+    events: EventBus,
+    clock: Arc<dyn Clock>,
+}
+
+impl Supervisor {
+    pub fn new(strategy: Strategy) -> Self {
+        Self {
+            children: Vec::new(),
+            strategy,
+            restart_limit: 5,
+            max_escalations: 1,
+            escalations: 0,
+            rng: XorShift::from_time(),
+            events: EventBus::new(),
+            clock: system_clock(),
+        }
+    }
+
+    /// Maximum start attempts in a row, and restarts in total, for services with the `Always`
+    /// policy (default 5)
+    pub fn with_restart_limit(mut self, limit: u32) -> Self {
+        self.restart_limit = limit;
+        self
+    }
+
+    /// How many times the strategy can restart other services before giving up (default 1).
+    /// The count starts again once every service is running
+    pub fn with_max_escalations(mut self, max: u32) -> Self {
+        self.max_escalations = max;
+        self
+    }
+
+    /// Seed for backoff jitter, useful to get repeatable delays
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = XorShift::new(seed);
+        self
+    }
+
+    /// Bus to report every start, stop and retry of the supervised services to
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Clock backoff delays are waited on
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn add(&mut self, name: &str, service: Box<dyn StartableService>, policy: RestartPolicy) {
+        self.children.push(Child {
+            name: name.to_owned(),
+            service,
+            policy,
+            attempts: 0,
+            spent: 0,
+            restarts: 0,
+            status: ChildStatus::Pending,
+            probe: None,
+        });
+    }
+
+    /// Checks the service with `probe` instead of its own `health` in `check_health`. A probe
+    /// which turns unhealthy restarts the service if its policy allows
+    pub fn add_probe(&mut self, name: &str, probe: Probe) -> Result<(), Error> {
+        let i = self
+            .find(name)
+            .ok_or_else(|| Error::UnknownService(name.to_owned()))?;
+        self.children[i].probe = Some(probe);
+        Ok(())
+    }
+
+    /// Last known health of the service, from its probe if it has one
+    pub fn health(&self, name: &str) -> Option<Health> {
+        self.find(name).map(|i| {
+            let child = &self.children[i];
+            match &child.probe {
+                Some(probe) => probe.health().clone(),
+                None => child.service.health(),
+            }
+        })
+    }
+
+    pub fn status(&self, name: &str) -> Option<&ChildStatus> {
+        self.find(name).map(|i| &self.children[i].status)
+    }
+
+    /// Number of times the service was started again after its first attempt
+    pub fn restarts(&self, name: &str) -> Option<u32> {
+        self.find(name).map(|i| self.children[i].restarts)
+    }
+
+    /// Starts every pending service in the order added. Returns an error for the first service
+    /// which could not be started, even after escalating. Escalations are counted from zero
+    /// again once every service has started
+    pub fn start(&mut self) -> Result<(), Error> {
+        let res = self.start_pending();
+        if res.is_ok() {
+            self.escalations = 0;
+        }
+        res
+    }
+
+    /// Same as `start`, but keeps counting escalations, so a service which keeps failing after
+    /// it started can not escalate without bound
+    fn start_pending(&mut self) -> Result<(), Error> {
+        let mut first_err = None;
+        let mut i = 0;
+        while i < self.children.len() {
+            if self.children[i].status == ChildStatus::Pending {
+                if let Err((restart_from, e)) = self.run(i) {
+                    if let Some(from) = restart_from {
+                        // Services from this index were reset to pending, start them again
+                        i = from;
+                        continue;
+                    }
+                    first_err.get_or_insert(e);
+                }
+            }
+            i += 1;
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+
+    /// Reports that a running service exited, restarting it if its policy allows. Errors if the
+    /// service has used up the attempts of its policy, after applying the strategy
+    pub fn exited(&mut self, name: &str, result: Result<(), String>) -> Result<(), Error> {
+        let i = self
+            .find(name)
+            .ok_or_else(|| Error::UnknownService(name.to_owned()))?;
+        let restart = match (&self.children[i].policy, &result) {
+            (RestartPolicy::Never, _) => false,
+            (RestartPolicy::Always, _) => true,
+            (_, res) => res.is_err(),
+        };
+        let max = self.max_attempts(&self.children[i].policy);
+        let child = &mut self.children[i];
+        child.status = match result {
+            Ok(()) => ChildStatus::Exited,
+            Err(e) => ChildStatus::Failed(e),
+        };
+        if !restart {
+            return Ok(());
+        }
+        let spent = child.budget_spent();
+        if spent >= max {
+            let err = Error::RestartBudgetExceeded {
+                service: child.name.clone(),
+                attempts: spent,
+            };
+            if self.escalate(i).is_some() {
+                let _ = self.start_pending();
+            }
+            return Err(err);
+        }
+        child.status = ChildStatus::Pending;
+        child.restarts += 1;
+        self.start_pending()
+    }
+
+    /// Checks every running service whose probe is due. Unhealthy services are stopped and
+    /// treated as failed, so they are restarted if their policy allows
+    pub fn check_health(&mut self) -> Result<(), Error> {
+        let mut first_err = None;
+        for i in 0..self.children.len() {
+            let child = &mut self.children[i];
+            if child.status != ChildStatus::Running {
+                continue;
+            }
+            let health = match &mut child.probe {
+                Some(probe) => probe.poll().clone(),
+                None => child.service.health(),
+            };
+            let reason = match health {
+                Health::Healthy => continue,
+                Health::Unhealthy(reason) => reason,
+            };
+            if let Some(probe) = &mut child.probe {
+                probe.reset();
+            }
+            let service = &mut child.service;
+            let _ = self
+                .events
+                .observe_stop(&child.name, || service.stop_typed());
+            let name = child.name.clone();
+            if let Err(e) = self.exited(&name, Err(format!("unhealthy: {}", reason))) {
+                first_err.get_or_insert(e);
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+
+    /// Stops every running service in the reverse order they were added
+    pub fn stop(&mut self) -> Result<(), Error> {
+        let mut first_err = None;
+        for child in self.children.iter_mut().rev() {
+            if child.status != ChildStatus::Running {
+                continue;
+            }
+            let service = &mut child.service;
+            match self
+                .events
+                .observe_stop(&child.name, || service.stop_typed())
+            {
+                Ok(()) => child.status = ChildStatus::Exited,
+                Err(e) => {
+                    child.status = ChildStatus::Failed(message(&e));
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.children.iter().position(|c| c.name == name)
+    }
+
+    fn max_attempts(&self, policy: &RestartPolicy) -> u32 {
+        match policy {
+            RestartPolicy::Never => 1,
+            RestartPolicy::Always => self.restart_limit,
+            RestartPolicy::OnFailure { max_attempts } => *max_attempts,
+            RestartPolicy::Backoff(b) => b.max_attempts,
+        }
+    }
+
+    /// Starts a single service until it succeeds or its attempts run out. On failure, returns
+    /// the index to continue starting from if the strategy reset other services
+    fn run(&mut self, i: usize) -> Result<(), (Option<usize>, Error)> {
+        let max = self.max_attempts(&self.children[i].policy);
+        loop {
+            let child = &mut self.children[i];
+            if child.attempts > 0 {
+                child.restarts += 1;
+                let delay = match &child.policy {
+                    RestartPolicy::Backoff(b) => b.jittered(child.attempts, &mut self.rng),
+                    _ => Duration::from_secs(0),
+                };
+                let attempt = child.attempts + 1;
+                self.events
+                    .emit(&child.name, EventKind::Retrying { attempt, delay }, None);
+                self.clock.sleep(delay);
+            }
+            child.attempts += 1;
+            child.spent += 1;
+            let service = &mut child.service;
+            match self
+                .events
+                .observe_start(&child.name, || service.start_typed())
+            {
+                Ok(()) => {
+                    child.attempts = 0;
+                    child.status = ChildStatus::Running;
+                    return Ok(());
+                }
+                Err(e) if child.attempts >= max || child.budget_spent() >= max => {
+                    let err = Error::RestartBudgetExceeded {
+                        service: child.name.clone(),
+                        attempts: child.attempts,
+                    };
+                    child.status = ChildStatus::Failed(message(&e));
+                    return Err((self.escalate(i), err));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Applies the strategy after service `i` ran out of attempts, returning the first index
+    /// that was reset to pending
+    fn escalate(&mut self, i: usize) -> Option<usize> {
+        let from = match self.strategy {
+            Strategy::OneForOne => return None,
+            Strategy::OneForAll => 0,
+            Strategy::RestForOne => i,
+        };
+        if self.escalations >= self.max_escalations {
+            return None;
+        }
+        self.escalations += 1;
+        // Running services are stopped before being restarted, latest started first
+        for child in self.children[from..].iter_mut().rev() {
+            if child.status == ChildStatus::Running {
+                let service = &mut child.service;
+                let _ = self
+                    .events
+                    .observe_stop(&child.name, || service.stop_typed());
+            }
+        }
+        for child in &mut self.children[from..] {
+            if child.status != ChildStatus::Pending {
+                child.restarts += 1;
+            }
+            child.attempts = 0;
+            child.spent = 0;
+            child.status = ChildStatus::Pending;
+        }
+        Some(from)
+    }
+}
+
+/// Small xorshift generator, only used to spread out backoff delays
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> Self {
+        // State must never be zero
+        Self(seed | 1)
+    }
+
+    pub(crate) fn from_time() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::new(seed)
+    }
+
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ServiceOne, ServiceThree, ServiceTwo};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// Fails until it has been started `fail_times` times
+    struct Flaky {
+        fail_times: u32,
+        starts: Arc<AtomicU32>,
+    }
+    impl StartableService for Flaky {
+        fn start(&mut self) -> Result<(), String> {
+            let n = self.starts.fetch_add(1, Ordering::SeqCst) + 1;
+            if n <= self.fail_times {
+                Err(format!("attempt {} failed", n))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn flaky(fail_times: u32) -> (Box<Flaky>, Arc<AtomicU32>) {
+        let starts = Arc::new(AtomicU32::new(0));
+        let s = Flaky {
+            fail_times,
+            starts: starts.clone(),
+        };
+        (Box::new(s), starts)
+    }
+
+    #[test]
+    fn restarts_until_started() {
+        let (s, starts) = flaky(2);
+        let mut sup = Supervisor::new(Strategy::OneForOne);
+        sup.add("one", Box::new(ServiceOne), RestartPolicy::Never);
+        sup.add("flaky", s, RestartPolicy::OnFailure { max_attempts: 3 });
+        assert_eq!(sup.start(), Ok(()));
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(sup.status("flaky"), Some(&ChildStatus::Running));
+        assert_eq!(sup.restarts("flaky"), Some(2));
+    }
+
+    #[test]
+    fn one_for_one_gives_up_on_single_service() {
+        let mut sup = Supervisor::new(Strategy::OneForOne);
+        sup.add(
+            "two",
+            Box::new(ServiceTwo),
+            RestartPolicy::OnFailure { max_attempts: 2 },
+        );
+        sup.add(
+            "three",
+            Box::new(ServiceThree { fails: false }),
+            RestartPolicy::Never,
+        );
+        assert_eq!(
+            sup.start(),
+            Err(Error::RestartBudgetExceeded {
+                service: "two".to_owned(),
+                attempts: 2
+            })
+        );
+        assert_eq!(
+            sup.status("two"),
+            Some(&ChildStatus::Failed("Service two failed!".to_owned()))
+        );
+        assert_eq!(sup.status("three"), Some(&ChildStatus::Running));
+    }
+
+    #[test]
+    fn escalation_restarts_other_services() {
+        let (first, first_starts) = flaky(0);
+        let (second, second_starts) = flaky(0);
+        // Fails twice, which exhausts its first budget, then starts after the escalation
+        let (last, last_starts) = flaky(2);
+
+        let mut sup = Supervisor::new(Strategy::RestForOne);
+        sup.add("first", first, RestartPolicy::Never);
+        sup.add("second", second, RestartPolicy::Never);
+        sup.add("last", last, RestartPolicy::OnFailure { max_attempts: 2 });
+        assert_eq!(sup.start(), Ok(()));
+        assert_eq!(first_starts.load(Ordering::SeqCst), 1);
+        assert_eq!(second_starts.load(Ordering::SeqCst), 1);
+        assert_eq!(last_starts.load(Ordering::SeqCst), 3);
+
+        let (first, first_starts) = flaky(0);
+        let (last, _) = flaky(2);
+        let mut sup = Supervisor::new(Strategy::OneForAll);
+        sup.add("first", first, RestartPolicy::Never);
+        sup.add("last", last, RestartPolicy::OnFailure { max_attempts: 2 });
+        assert_eq!(sup.start(), Ok(()));
+        assert_eq!(first_starts.load(Ordering::SeqCst), 2);
+        assert_eq!(sup.restarts("first"), Some(1));
+    }
+
+    #[test]
+    fn stop_in_reverse() {
+        use std::sync::Mutex;
+
+        struct Logged(&'static str, Arc<Mutex<Vec<&'static str>>>);
+        impl StartableService for Logged {
+            fn stop(&mut self) -> Result<(), String> {
+                self.1.lock().unwrap().push(self.0);
+                Ok(())
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut sup = Supervisor::new(Strategy::OneForOne);
+        sup.add(
+            "a",
+            Box::new(Logged("a", log.clone())),
+            RestartPolicy::Never,
+        );
+        sup.add(
+            "b",
+            Box::new(Logged("b", log.clone())),
+            RestartPolicy::Never,
+        );
+        sup.start().unwrap();
+        sup.stop().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["b", "a"]);
+        assert_eq!(sup.status("a"), Some(&ChildStatus::Exited));
+    }
+
+    #[test]
+    fn retry_events() {
+        let bus = EventBus::new();
+        let rx = bus.channel();
+        let (s, _) = flaky(1);
+        let mut sup = Supervisor::new(Strategy::OneForOne).with_events(bus);
+        sup.add("flaky", s, RestartPolicy::OnFailure { max_attempts: 2 });
+        sup.start().unwrap();
+        let kinds: Vec<_> = rx.try_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Starting,
+                EventKind::Failed {
+                    variant: "Custom",
+                    message: "attempt 1 failed".to_owned()
+                },
+                EventKind::Retrying {
+                    attempt: 2,
+                    delay: Duration::from_secs(0)
+                },
+                EventKind::Starting,
+                EventKind::Started,
+            ]
+        );
+    }
+
+    #[test]
+    fn escalation_limit() {
+        let (first, first_starts) = flaky(0);
+        let mut sup = Supervisor::new(Strategy::OneForAll).with_max_escalations(2);
+        sup.add("first", first, RestartPolicy::Never);
+        sup.add(
+            "three",
+            Box::new(ServiceThree { fails: true }),
+            RestartPolicy::Never,
+        );
+        assert!(sup.start().is_err());
+        // Started once initially, then once for each escalation
+        assert_eq!(first_starts.load(Ordering::SeqCst), 3);
+
+        // Once everything runs again, escalations are counted from zero
+        let (s, s_starts) = flaky(1);
+        let mut sup = Supervisor::new(Strategy::OneForAll);
+        sup.add("first", Box::new(ServiceOne), RestartPolicy::Never);
+        sup.add("flaky", s, RestartPolicy::Never);
+        assert_eq!(sup.start(), Ok(()));
+        assert_eq!(s_starts.load(Ordering::SeqCst), 2);
+        assert_eq!(sup.escalations, 0);
+    }
+
+    #[test]
+    fn restart_on_exit() {
+        let (s, starts) = flaky(0);
+        let mut sup = Supervisor::new(Strategy::OneForOne);
+        sup.add("always", s, RestartPolicy::Always);
+        sup.add("never", Box::new(ServiceOne), RestartPolicy::Never);
+        sup.start().unwrap();
+
+        sup.exited("always", Ok(())).unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(sup.status("always"), Some(&ChildStatus::Running));
+
+        sup.exited("never", Err("crashed".to_owned())).unwrap();
+        assert_eq!(
+            sup.status("never"),
+            Some(&ChildStatus::Failed("crashed".to_owned()))
+        );
+    }
+
+    #[test]
+    fn crash_loop_escalates() {
+        let (s, starts) = flaky(0);
+        let (other, other_starts) = flaky(0);
+        let mut sup = Supervisor::new(Strategy::OneForAll);
+        sup.add("other", other, RestartPolicy::Never);
+        sup.add("crashy", s, RestartPolicy::OnFailure { max_attempts: 2 });
+        sup.start().unwrap();
+
+        let crash = || Err("crashed".to_owned());
+        let exceeded = Err(Error::RestartBudgetExceeded {
+            service: "crashy".to_owned(),
+            attempts: 2,
+        });
+        // Started fine every time, but the crashes count against the two attempts
+        sup.exited("crashy", crash()).unwrap();
+        assert_eq!(sup.exited("crashy", crash()), exceeded);
+        // Strategy restarted both, with a fresh budget for the crashing service
+        assert_eq!(other_starts.load(Ordering::SeqCst), 2);
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(sup.status("crashy"), Some(&ChildStatus::Running));
+
+        // Out of escalations, so the service is given up on
+        sup.exited("crashy", crash()).unwrap();
+        assert_eq!(sup.exited("crashy", crash()), exceeded);
+        assert_eq!(starts.load(Ordering::SeqCst), 4);
+        assert_eq!(other_starts.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            sup.status("crashy"),
+            Some(&ChildStatus::Failed("crashed".to_owned()))
+        );
+    }
+
+    #[test]
+    fn always_stops_at_restart_limit() {
+        let (s, starts) = flaky(0);
+        let mut sup = Supervisor::new(Strategy::OneForOne).with_restart_limit(2);
+        sup.add("always", s, RestartPolicy::Always);
+        sup.start().unwrap();
+        sup.exited("always", Ok(())).unwrap();
+        sup.exited("always", Err("crashed".to_owned())).unwrap();
+        assert_eq!(
+            sup.exited("always", Ok(())),
+            Err(Error::RestartBudgetExceeded {
+                service: "always".to_owned(),
+                attempts: 2,
+            })
+        );
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(sup.status("always"), Some(&ChildStatus::Exited));
+    }
+
+    #[test]
+    fn restart_unhealthy_services() {
+        use crate::Check;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = || {
+            Probe::new(Check::tcp_port(port))
+                .interval(Duration::from_secs(0))
+                .failure_threshold(2)
+        };
+        let (s, starts) = flaky(0);
+        let mut sup = Supervisor::new(Strategy::OneForOne);
+        sup.add("server", s, RestartPolicy::OnFailure { max_attempts: 3 });
+        sup.add("never", Box::new(ServiceOne), RestartPolicy::Never);
+        sup.add_probe("server", probe()).unwrap();
+        sup.add_probe("never", probe()).unwrap();
+        assert!(sup.add_probe("missing", probe()).is_err());
+        sup.start().unwrap();
+
+        sup.check_health().unwrap();
+        assert_eq!(sup.health("server"), Some(Health::Healthy));
+
+        drop(listener);
+        // Below the failure threshold, nothing is restarted yet
+        sup.check_health().unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        sup.check_health().unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(sup.status("server"), Some(&ChildStatus::Running));
+        assert_eq!(sup.restarts("server"), Some(1));
+        match sup.status("never") {
+            Some(ChildStatus::Failed(e)) => assert!(e.starts_with("unhealthy: ")),
+            other => panic!("unexpected status {:?}", other),
+        }
+    }
+
+    #[test]
+    fn backoff_on_simulated_clock() {
+        use crate::{Fault, FaultyService, SimClock};
+
+        let clock = Arc::new(SimClock::new());
+        let bus = EventBus::new().with_clock(clock.clone());
+        let rx = bus.channel();
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 3,
+            jitter: 0.0,
+            max_attempts: 4,
+        };
+        let mut sup = Supervisor::new(Strategy::OneForOne)
+            .with_events(bus)
+            .with_clock(clock.clone());
+        sup.add(
+            "faulty",
+            Box::new(FaultyService::new(Fault::FailFirst(3))),
+            RestartPolicy::Backoff(backoff),
+        );
+        assert_eq!(sup.start(), Ok(()));
+        assert_eq!(
+            clock.sleeps(),
+            vec![
+                Duration::from_secs(1),
+                Duration::from_secs(3),
+                Duration::from_secs(9)
+            ]
+        );
+
+        let start = clock.system_time() - clock.elapsed();
+        let attempts: Vec<_> = rx
+            .try_iter()
+            .filter(|e| e.kind == EventKind::Starting)
+            .map(|e| e.at.duration_since(start).unwrap().as_secs())
+            .collect();
+        assert_eq!(attempts, vec![0, 1, 4, 13]);
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let b = Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(6),
+            multiplier: 2,
+            jitter: 0.5,
+            max_attempts: 4,
+        };
+        assert_eq!(b.delay(1), Duration::from_millis(1));
+        assert_eq!(b.delay(2), Duration::from_millis(2));
+        assert_eq!(b.delay(3), Duration::from_millis(4));
+        assert_eq!(b.delay(4), Duration::from_millis(6));
+        assert_eq!(b.delay(100), Duration::from_millis(6));
+
+        let (s, starts) = flaky(3);
+        let mut sup = Supervisor::new(Strategy::OneForOne).with_seed(7);
+        sup.add("flaky", s, RestartPolicy::Backoff(b.clone()));
+        assert_eq!(sup.start(), Ok(()));
+        assert_eq!(starts.load(Ordering::SeqCst), 4);
+
+        let nan = Backoff {
+            jitter: f64::NAN,
+            ..b
+        };
+        assert_eq!(
+            nan.jittered(3, &mut XorShift::new(7)),
+            Duration::from_millis(4)
+        );
+    }
+}
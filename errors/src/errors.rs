@@ -1,16 +1,64 @@
+use std::convert::TryFrom;
+use std::error;
 use std::fmt;
+use std::io;
 
 // Can define different types of error in enums
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum Error {
     BaseError,
     ParameterError(String),
     TwoParameterError(String, u8),
-    StructError { name: String, number: u8 },
+    StructError {
+        name: String,
+        number: u8,
+    },
     NestedError(OtherError),
+    // Keeps the original error, so callers can still check its kind
+    Io(io::Error),
+    // What was being done when `source` happened, see the `Context` trait
+    Context {
+        context: String,
+        source: Option<Box<dyn error::Error + Send + Sync>>,
+    },
     Other,
 }
 
+// * io::Error does not implement PartialEq, so equality is implemented by hand, comparing io
+// * errors by their kind only
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        match (self, other) {
+            (Error::BaseError, Error::BaseError) => true,
+            (Error::ParameterError(a), Error::ParameterError(b)) => a == b,
+            (Error::TwoParameterError(a, n), Error::TwoParameterError(b, m)) => a == b && n == m,
+            (
+                Error::StructError { name, number },
+                Error::StructError {
+                    name: other_name,
+                    number: other_number,
+                },
+            ) => name == other_name && number == other_number,
+            (Error::NestedError(a), Error::NestedError(b)) => a == b,
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+            // * Boxed errors can not be compared either, so their messages are compared instead
+            (
+                Error::Context { context, source },
+                Error::Context {
+                    context: other_context,
+                    source: other_source,
+                },
+            ) => {
+                context == other_context
+                    && source.as_ref().map(|e| e.to_string())
+                        == other_source.as_ref().map(|e| e.to_string())
+            }
+            (Error::Other, Error::Other) => true,
+            _ => false,
+        }
+    }
+}
+
 // Implementing to_string for Error
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -25,12 +73,14 @@ impl fmt::Display for Error {
                 number: num,
             } => write!(f, "name: {}, num: {}", n, num),
             Error::NestedError(ref err) => write!(f, "Nested error, err inside is: {}", err),
+            Error::Io(ref err) => write!(f, "IO error: {}", err),
+            Error::Context { ref context, .. } => write!(f, "{}", context),
             Error::Other => write!(f, "Unknown error"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OtherError {
     SimpleError,
 }
@@ -42,3 +92,222 @@ impl fmt::Display for OtherError {
         }
     }
 }
+
+// Stable code of each variant, which must never change once published since clients key
+// alerting on them. Every conversion between codes and variants goes through this table.
+// Variants with a payload only have a code for the variant, the payload has to be sent along
+// with it:
+//
+//   code  variant            payload
+//   0     Other
+//   1     BaseError
+//   2     ParameterError     message
+//   3     TwoParameterError  message, number
+//   4     StructError        name, number
+//   5     NestedError        inner error
+//   6     Io                 io error
+//   7     Context            context, source error
+const CODES: [(u16, &str); 8] = [
+    (0, "Other"),
+    (1, "BaseError"),
+    (2, "ParameterError"),
+    (3, "TwoParameterError"),
+    (4, "StructError"),
+    (5, "NestedError"),
+    (6, "Io"),
+    (7, "Context"),
+];
+
+// What a variant holds besides its code, see `Error::into_parts`
+#[derive(Debug)]
+pub enum Payload {
+    None,
+    Message(String),
+    MessageNumber(String, u8),
+    NameNumber {
+        name: String,
+        number: u8,
+    },
+    Nested(OtherError),
+    Io(io::Error),
+    Context {
+        context: String,
+        source: Option<Box<dyn error::Error + Send + Sync>>,
+    },
+}
+
+impl Error {
+    pub fn code(&self) -> u16 {
+        let variant = self.variant();
+        CODES
+            .iter()
+            .find(|(_, v)| *v == variant)
+            .map(|(code, _)| *code)
+            .expect("every variant is in the code table")
+    }
+
+    // Splits the error into its code and payload, which `from_code_and_payload` builds the
+    // same error from again
+    pub fn into_parts(self) -> (u16, Payload) {
+        let code = self.code();
+        let payload = match self {
+            Error::Other | Error::BaseError => Payload::None,
+            Error::ParameterError(message) => Payload::Message(message),
+            Error::TwoParameterError(message, number) => Payload::MessageNumber(message, number),
+            Error::StructError { name, number } => Payload::NameNumber { name, number },
+            Error::NestedError(inner) => Payload::Nested(inner),
+            Error::Io(e) => Payload::Io(e),
+            Error::Context { context, source } => Payload::Context { context, source },
+        };
+        (code, payload)
+    }
+
+    pub fn from_code_and_payload(code: u16, payload: Payload) -> Result<Self, CodeError> {
+        let variant = CODES
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| *v)
+            .ok_or(CodeError::Unknown(code))?;
+        Ok(match (variant, payload) {
+            ("Other", Payload::None) => Error::Other,
+            ("BaseError", Payload::None) => Error::BaseError,
+            ("ParameterError", Payload::Message(message)) => Error::ParameterError(message),
+            ("TwoParameterError", Payload::MessageNumber(message, number)) => {
+                Error::TwoParameterError(message, number)
+            }
+            ("StructError", Payload::NameNumber { name, number }) => {
+                Error::StructError { name, number }
+            }
+            ("NestedError", Payload::Nested(inner)) => Error::NestedError(inner),
+            ("Io", Payload::Io(e)) => Error::Io(e),
+            ("Context", Payload::Context { context, source }) => Error::Context { context, source },
+            (_, Payload::None) => return Err(CodeError::PayloadRequired(code)),
+            _ => return Err(CodeError::PayloadMismatch(code)),
+        })
+    }
+
+    // Name of the variant, as used in the code table
+    fn variant(&self) -> &'static str {
+        // * No wildcard arm, so a new variant does not compile until it is named here, and the
+        // * code table tests then fail until it is given a code
+        match self {
+            Error::Other => "Other",
+            Error::BaseError => "BaseError",
+            Error::ParameterError(_) => "ParameterError",
+            Error::TwoParameterError(_, _) => "TwoParameterError",
+            Error::StructError { .. } => "StructError",
+            Error::NestedError(_) => "NestedError",
+            Error::Io(_) => "Io",
+            Error::Context { .. } => "Context",
+        }
+    }
+
+    // Iterator over this error, then each error that caused it
+    pub fn chain(&self) -> Chain<'_> {
+        Chain { next: Some(self) }
+    }
+
+    // Displays the whole chain, one error per line
+    pub fn report(&self) -> Report<'_> {
+        Report(self)
+    }
+}
+
+// Why a code could not be turned back into an `Error`
+#[derive(Debug, PartialEq)]
+pub enum CodeError {
+    Unknown(u16),
+    // Code belongs to a variant which can not be built without its payload
+    PayloadRequired(u16),
+    // Payload is not the one the code's variant holds
+    PayloadMismatch(u16),
+}
+
+impl fmt::Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CodeError::Unknown(code) => write!(f, "Unknown error code {}", code),
+            CodeError::PayloadRequired(code) => {
+                write!(f, "Error code {} needs a payload to be built", code)
+            }
+            CodeError::PayloadMismatch(code) => {
+                write!(f, "Payload does not match error code {}", code)
+            }
+        }
+    }
+}
+
+// Unlike From<u8>, codes which are not in the table are rejected instead of becoming Other
+impl TryFrom<u16> for Error {
+    type Error = CodeError;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        Error::from_code_and_payload(code, Payload::None)
+    }
+}
+
+// Follows `source` from an error until it reaches one without a cause
+pub struct Chain<'a> {
+    next: Option<&'a (dyn error::Error + 'static)>,
+}
+
+impl<'a> Iterator for Chain<'a> {
+    type Item = &'a (dyn error::Error + 'static);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next?;
+        self.next = current.source();
+        Some(current)
+    }
+}
+
+// Renders any error as "error: ..." followed by a "caused by: ..." line for each cause
+pub struct Report<'a>(pub &'a (dyn error::Error + 'static));
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error: {}", self.0)?;
+        let mut cause = self.0.source();
+        while let Some(e) = cause {
+            write!(f, "\ncaused by: {}", e)?;
+            cause = e.source();
+        }
+        Ok(())
+    }
+}
+
+// Extension trait to say what was being done when an error happened, by wrapping it in an
+// `Error::Context` which keeps the original error as its source
+pub trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, Error>;
+    // Same as `context`, but the context is only built if there is an error
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T, Error>;
+}
+
+impl<T, E> Context<T> for Result<T, E>
+where
+    E: error::Error + Send + Sync + 'static,
+{
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, Error> {
+        self.with_context(|| context)
+    }
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T, Error> {
+        self.map_err(|e| Error::Context {
+            context: f().to_string(),
+            source: Some(Box::new(e)),
+        })
+    }
+}
+
+// * None has no error to keep, so the context becomes the error itself
+impl<T> Context<T> for Option<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, Error> {
+        self.with_context(|| context)
+    }
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T, Error> {
+        self.ok_or_else(|| Error::Context {
+            context: f().to_string(),
+            source: None,
+        })
+    }
+}
@@ -1,4 +1,9 @@
 mod errors;
+// Serialize and Deserialize for errors::Error, only built with the serde feature
+#[cfg(feature = "serde")]
+mod wire;
+
+pub use errors::{Chain, CodeError, Context, Payload, Report};
 
 pub fn returns_ok() -> Result<(), errors::Error> {
     Ok(())
@@ -41,8 +46,9 @@ pub fn upgrade_error() -> Result<(), errors::Error> {
 
 // This can/ should be put into the errors.rs file, but I am keeping here for readability
 impl From<std::io::Error> for errors::Error {
-    fn from(_e: std::io::Error) -> errors::Error {
-        errors::Error::Other
+    fn from(e: std::io::Error) -> errors::Error {
+        // Wrapping the error instead of dropping it keeps its kind and message
+        errors::Error::Io(e)
     }
 }
 
@@ -72,16 +78,24 @@ pub fn you_can_use_anything_lol<T>(
 }
 
 impl std::error::Error for errors::Error {
-    fn description(&self) -> &str {
-        // could match to get enum types
-        "description of an implementation of an Error"
-    }
-    fn cause(&self) -> Option<&dyn std::error::Error> {
-        // No need to implement cause
-        None
+    // * description and cause are deprecated, source is the only method needed to link an error
+    // * to the one that caused it
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            errors::Error::NestedError(e) => Some(e),
+            errors::Error::Io(e) => Some(e),
+            errors::Error::Context {
+                source: Some(e), ..
+            } => Some(&**e),
+            _ => None,
+        }
     }
 }
 
+impl std::error::Error for errors::OtherError {}
+
+impl std::error::Error for errors::CodeError {}
+
 // ? side note, you can convert any types using the .into() function, it's pretty neat
 pub fn converting_type(i: u8) -> errors::Error {
     i.into()
@@ -190,8 +204,184 @@ mod test {
     #[test]
     fn upgrading_error() {
         let res = upgrade_error();
-        assert!(res.unwrap_err() == Error::Other);
+        let err = res.unwrap_err();
+        // * Io errors are equal if their kind is, the message is not compared
+        assert!(err == Error::Io(std::io::ErrorKind::Other.into()));
+        assert_eq!(err.to_string(), "IO error: Test standard error");
+    }
+
+    #[test]
+    fn io_error_kinds() {
+        use std::error::Error as _;
+        use std::io::ErrorKind;
+
+        let not_found: Error = std::fs::read("/this/file/does/not/exist")
+            .unwrap_err()
+            .into();
+        // * Can still match on the kind of the original error
+        match &not_found {
+            Error::Io(e) if e.kind() == ErrorKind::NotFound => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_ne!(not_found, Error::Io(ErrorKind::PermissionDenied.into()));
+        assert_ne!(not_found, Error::Other);
+
+        // * Original error is available through source
+        let source = not_found.source().unwrap();
+        let io = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), ErrorKind::NotFound);
+        assert!(Error::Other.source().is_none());
+    }
+    #[test]
+    fn cause_chain() {
+        use std::error::Error as _;
+
+        let err = Error::NestedError(errors::OtherError::SimpleError);
+        let chain: Vec<_> = err.chain().map(|e| e.to_string()).collect();
+        assert_eq!(
+            chain,
+            vec![
+                "Nested error, err inside is: Other simple error",
+                "Other simple error"
+            ]
+        );
+        assert!(err.source().unwrap().is::<errors::OtherError>());
+        assert_eq!(
+            err.report().to_string(),
+            "error: Nested error, err inside is: Other simple error\n\
+             caused by: Other simple error"
+        );
+
+        // * Report works for any error, and only has a single line without a cause
+        assert_eq!(Report(&Error::BaseError).to_string(), "error: Base Error");
+        let chain: Chain<'_> = Error::Other.chain();
+        assert_eq!(chain.count(), 1);
+    }
+
+    #[test]
+    fn adding_context() {
+        use errors::Context;
+
+        // * Unlike ok_or(Error::Other), the error says what was missing
+        let err = returns_error(None)
+            .context("while loading the config")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "while loading the config");
+        assert_eq!(
+            err.report().to_string(),
+            "error: while loading the config\ncaused by: Unknown error"
+        );
+
+        let missing: Option<u8> = None;
+        let err = missing.context("no port set").unwrap_err();
+        assert_eq!(
+            err,
+            Error::Context {
+                context: "no port set".to_owned(),
+                source: None,
+            }
+        );
+
+        // * with_context only builds the message on error, and contexts can be stacked
+        let mut built = false;
+        let ok = returns_ok().with_context(|| {
+            built = true;
+            "never built"
+        });
+        assert!(ok.is_ok() && !built);
+        let err = upgrade_error()
+            .with_context(|| format!("while reading {}", "services.toml"))
+            .context("while starting")
+            .unwrap_err();
+        let chain: Vec<_> = err.chain().map(|e| e.to_string()).collect();
+        assert_eq!(
+            chain,
+            vec![
+                "while starting",
+                "while reading services.toml",
+                "IO error: Test standard error",
+                "Test standard error",
+            ]
+        );
+    }
+
+    #[test]
+    fn error_codes() {
+        use std::convert::TryFrom;
+
+        // One of each variant. The match below has no wildcard, so adding a variant fails to
+        // compile here until it is added to this list as well
+        let every = vec![
+            Error::Other,
+            Error::BaseError,
+            Error::ParameterError("p".to_owned()),
+            Error::TwoParameterError("p".to_owned(), 2),
+            Error::StructError {
+                name: "s".to_owned(),
+                number: 4,
+            },
+            Error::NestedError(errors::OtherError::SimpleError),
+            Error::Io(std::io::ErrorKind::NotFound.into()),
+            Error::Context {
+                context: "c".to_owned(),
+                source: None,
+            },
+        ];
+        let mut listed = 0;
+        for e in &every {
+            match e {
+                Error::BaseError
+                | Error::ParameterError(_)
+                | Error::TwoParameterError(_, _)
+                | Error::StructError { .. }
+                | Error::NestedError(_)
+                | Error::Io(_)
+                | Error::Context { .. }
+                | Error::Other => listed += 1,
+            }
+        }
+        assert_eq!(listed, 8);
+
+        // * Codes are the documented table, so they can never be reused or renumbered
+        let codes: Vec<u16> = every.iter().map(Error::code).collect();
+        assert_eq!(codes, vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+        for e in &every {
+            match Error::try_from(e.code()) {
+                Ok(back) => assert_eq!(&back, e),
+                Err(err) => assert_eq!(err, CodeError::PayloadRequired(e.code())),
+            }
+        }
+
+        // * Every variant is built again from its code and payload
+        for e in every {
+            let expected = e.to_string();
+            let (code, payload) = e.into_parts();
+            let back = Error::from_code_and_payload(code, payload).unwrap();
+            assert_eq!(back.code(), code);
+            assert_eq!(back.to_string(), expected);
+        }
+        assert_eq!(
+            Error::from_code_and_payload(1, Payload::Message("m".to_owned())).unwrap_err(),
+            CodeError::PayloadMismatch(1)
+        );
+        assert_eq!(
+            Error::from_code_and_payload(2, Payload::MessageNumber("m".to_owned(), 2)).unwrap_err(),
+            CodeError::PayloadMismatch(2)
+        );
+        assert_eq!(
+            Error::from_code_and_payload(8, Payload::None).unwrap_err(),
+            CodeError::Unknown(8)
+        );
+        assert_eq!(Error::try_from(8u16), Err(CodeError::Unknown(8)));
+        assert_eq!(
+            Error::try_from(u16::MAX).unwrap_err().to_string(),
+            "Unknown error code 65535"
+        );
+        // From<u8> keeps mapping unknown values to Other
+        assert_eq!(Error::from(8), Error::Other);
     }
+
     #[test]
     fn upgrade_example() {
         let err = converting_type(1);
@@ -0,0 +1,244 @@
+use crate::errors::{Error, OtherError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+
+// Version of the representation below, bumped if a variant's fields ever change. Newer
+// versions are still read, with variants this version does not know becoming Error::Other,
+// including unknown variants of a nested OtherError
+const VERSION: u32 = 1;
+
+// Every io error kind which is sent by name, any other kind is sent as "Other"
+const IO_KINDS: [(io::ErrorKind, &str); 18] = [
+    (io::ErrorKind::NotFound, "NotFound"),
+    (io::ErrorKind::PermissionDenied, "PermissionDenied"),
+    (io::ErrorKind::ConnectionRefused, "ConnectionRefused"),
+    (io::ErrorKind::ConnectionReset, "ConnectionReset"),
+    (io::ErrorKind::ConnectionAborted, "ConnectionAborted"),
+    (io::ErrorKind::NotConnected, "NotConnected"),
+    (io::ErrorKind::AddrInUse, "AddrInUse"),
+    (io::ErrorKind::AddrNotAvailable, "AddrNotAvailable"),
+    (io::ErrorKind::BrokenPipe, "BrokenPipe"),
+    (io::ErrorKind::AlreadyExists, "AlreadyExists"),
+    (io::ErrorKind::WouldBlock, "WouldBlock"),
+    (io::ErrorKind::InvalidInput, "InvalidInput"),
+    (io::ErrorKind::InvalidData, "InvalidData"),
+    (io::ErrorKind::TimedOut, "TimedOut"),
+    (io::ErrorKind::WriteZero, "WriteZero"),
+    (io::ErrorKind::Interrupted, "Interrupted"),
+    (io::ErrorKind::UnexpectedEof, "UnexpectedEof"),
+    (io::ErrorKind::Other, "Other"),
+];
+
+// What is actually sent, ex: {"version":1,"type":"StructError","name":"austin","number":8}
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    #[serde(flatten)]
+    error: Repr,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Repr {
+    BaseError,
+    ParameterError {
+        message: String,
+    },
+    TwoParameterError {
+        message: String,
+        number: u8,
+    },
+    StructError {
+        name: String,
+        number: u8,
+    },
+    NestedError {
+        inner: OtherRepr,
+    },
+    Io {
+        kind: String,
+        message: String,
+    },
+    // * Source can be any error, so only its message is sent
+    Context {
+        context: String,
+        source: Option<String>,
+    },
+    // * Any type this version does not know, sent by a newer version
+    #[serde(other)]
+    Other,
+}
+
+// OtherError as sent inside a NestedError, ex: {"type":"SimpleError"}. Tagged the same way as
+// Repr so a newer variant, with or without fields, is still read
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum OtherRepr {
+    SimpleError,
+    #[serde(other)]
+    Unknown,
+}
+
+// Source of a Context error which was received, only its message is known
+#[derive(Debug)]
+struct Message(String);
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Message {}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let error = match self {
+            Error::BaseError => Repr::BaseError,
+            Error::ParameterError(message) => Repr::ParameterError {
+                message: message.clone(),
+            },
+            Error::TwoParameterError(message, number) => Repr::TwoParameterError {
+                message: message.clone(),
+                number: *number,
+            },
+            Error::StructError { name, number } => Repr::StructError {
+                name: name.clone(),
+                number: *number,
+            },
+            Error::NestedError(inner) => Repr::NestedError {
+                inner: match inner {
+                    OtherError::SimpleError => OtherRepr::SimpleError,
+                },
+            },
+            Error::Io(e) => Repr::Io {
+                kind: kind_name(e.kind()).to_owned(),
+                message: e.to_string(),
+            },
+            Error::Context { context, source } => Repr::Context {
+                context: context.clone(),
+                source: source.as_ref().map(|e| e.to_string()),
+            },
+            Error::Other => Repr::Other,
+        };
+        Envelope {
+            version: VERSION,
+            error,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let envelope = Envelope::deserialize(deserializer)?;
+        Ok(match envelope.error {
+            Repr::BaseError => Error::BaseError,
+            Repr::ParameterError { message } => Error::ParameterError(message),
+            Repr::TwoParameterError { message, number } => {
+                Error::TwoParameterError(message, number)
+            }
+            Repr::StructError { name, number } => Error::StructError { name, number },
+            Repr::NestedError {
+                inner: OtherRepr::SimpleError,
+            } => Error::NestedError(OtherError::SimpleError),
+            // * Nothing to nest, so the whole error is one this version does not know
+            Repr::NestedError {
+                inner: OtherRepr::Unknown,
+            } => Error::Other,
+            Repr::Io { kind, message } => Error::Io(io::Error::new(kind_from_name(&kind), message)),
+            Repr::Context { context, source } => Error::Context {
+                context,
+                source: source.map(|m| Box::new(Message(m)) as _),
+            },
+            Repr::Other => Error::Other,
+        })
+    }
+}
+
+fn kind_name(kind: io::ErrorKind) -> &'static str {
+    IO_KINDS
+        .iter()
+        .find(|(k, _)| *k == kind)
+        .map_or("Other", |(_, name)| name)
+}
+
+fn kind_from_name(name: &str) -> io::ErrorKind {
+    IO_KINDS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map_or(io::ErrorKind::Other, |(kind, _)| *kind)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn round_trip(e: &Error) -> Error {
+        serde_json::from_str(&serde_json::to_string(e).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let every = vec![
+            Error::BaseError,
+            Error::ParameterError("parameter!".to_owned()),
+            Error::TwoParameterError("first".to_owned(), 2),
+            Error::StructError {
+                name: "austin".to_owned(),
+                number: 8,
+            },
+            Error::NestedError(OtherError::SimpleError),
+            Error::Io(io::Error::new(io::ErrorKind::NotFound, "no services.toml")),
+            Error::Context {
+                context: "while loading".to_owned(),
+                source: Some(Box::new(Error::BaseError)),
+            },
+            Error::Other,
+        ];
+        for e in &every {
+            let back = round_trip(e);
+            assert_eq!(&back, e);
+            assert_eq!(back.to_string(), e.to_string());
+        }
+
+        assert_eq!(
+            serde_json::to_value(&every[3]).unwrap(),
+            json!({"version": 1, "type": "StructError", "name": "austin", "number": 8})
+        );
+        assert_eq!(
+            serde_json::to_value(&every[4]).unwrap(),
+            json!({"version": 1, "type": "NestedError", "inner": {"type": "SimpleError"}})
+        );
+        assert_eq!(
+            serde_json::to_value(&every[5]).unwrap(),
+            json!({"version": 1, "type": "Io", "kind": "NotFound", "message": "no services.toml"})
+        );
+    }
+
+    #[test]
+    fn unknown_variants_fall_back() {
+        let future = json!({"version": 2, "type": "QuotaError", "limit": 10});
+        let e: Error = serde_json::from_value(future).unwrap();
+        assert_eq!(e, Error::Other);
+
+        let nested = json!({
+            "version": 2,
+            "type": "NestedError",
+            "inner": {"type": "TimeoutError", "after_ms": 500}
+        });
+        let e: Error = serde_json::from_value(nested).unwrap();
+        assert_eq!(e, Error::Other);
+
+        let io = json!({"version": 1, "type": "Io", "kind": "SomeNewKind", "message": "m"});
+        match serde_json::from_value(io).unwrap() {
+            Error::Io(e) => assert_eq!(e.kind(), io::ErrorKind::Other),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        // * Still an error if the document is not an error at all
+        assert!(serde_json::from_value::<Error>(json!({"type": "BaseError"})).is_err());
+    }
+}